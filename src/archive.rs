@@ -0,0 +1,79 @@
+// 通过 archiver.php 下载图集的压缩包(原图)，消耗GP而不是图片配额
+// 流程：提交下载请求 -> 页面中给出H@H的下载地址 -> 加上 ?start=1 下载zip
+
+use crate::handler::{DownloadError, Handler};
+use crate::interrupt::InFlight;
+use crate::manga::Manga;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// 下载压缩包到 dir/<gid>.zip，返回文件路径
+/// 先写入 <gid>.zip.part，校验完整后才改名，中断或截断的下载不会被当成已归档
+pub fn download(m: &Manga, dir: &str, cookie: &str) -> Result<PathBuf, DownloadError> {
+    let archiver = m
+        .archiver_url
+        .as_deref()
+        .ok_or_else(|| DownloadError::Parse(format!("No archive download for gallery {}", m.number)))?;
+    let h = Handler::new(m.url.host_str().unwrap_or_default(), cookie);
+    let body = h.post_form(archiver, &[("dltype", "org"), ("dlcheck", "Download Original Archive")])?;
+
+    let link = match download_link(&body) {
+        Some(link) => link,
+        None if body.contains("Insufficient") => {
+            return Err(DownloadError::Parse("Insufficient GP for the archive download".to_string()))
+        }
+        None => {
+            return Err(DownloadError::Parse(format!(
+                "No archive download link in response: {}",
+                body.chars().take(100).collect::<String>().trim()
+            )))
+        }
+    };
+
+    let url = format!("{}?start=1", link.trim_end_matches("?start=1"));
+    let host = reqwest::Url::parse(&url)
+        .map_err(|e| DownloadError::Parse(format!("{}: {}", url, e)))?
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    let fname = path(dir, m.number);
+    let part = fname.with_extension("zip.part");
+    let _guard = InFlight::new(&part);
+    // 压缩包不是图片，但同样需要识别封禁提示和错误状态码
+    Handler::new(&host, cookie).save_large(&url, &part)?;
+
+    // zip 以 "PK" 开头，其他内容多半是错误页面
+    let mut magic = [0u8; 2];
+    let valid = File::open(&part)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic))
+        .is_ok()
+        && &magic == b"PK";
+    if !valid {
+        let _ = fs::remove_file(&part);
+        return Err(DownloadError::Verification(format!("Not a zip archive: {}", url)));
+    }
+    fs::rename(&part, &fname).map_err(DownloadError::Io)?;
+    Ok(fname)
+}
+
+/// 图集目录中压缩包的路径 <dir>/<gid>.zip
+pub fn path(dir: &str, gid: u32) -> PathBuf {
+    Path::new(dir).join(format!("{}.zip", gid))
+}
+
+// 下载地址在 document.location = "..." 中，旧版页面是 <a href="...">
+fn download_link(body: &str) -> Option<String> {
+    let quoted = |rest: &str| {
+        let rest = rest.trim_start().trim_start_matches('=').trim_start();
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let rest = &rest[1..];
+        Some(rest[..rest.find(quote)?].to_string())
+    };
+    if let Some(link) = body.split_once("document.location").and_then(|(_, rest)| quoted(rest)) {
+        return Some(link);
+    }
+    body.split("href=")
+        .skip(1)
+        .filter_map(quoted)
+        .find(|link| link.contains("/archive/"))
+}
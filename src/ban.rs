@@ -0,0 +1,93 @@
+// IP被封的检测与全局状态：任何一个线程检测到被封后，其他线程在封禁结束前都不再发送请求
+
+use crate::logger;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BAN_TEXT: &str = "This IP address has been temporarily banned";
+
+// 页面上没有写明剩余时间时按1小时处理
+const DEFAULT_BAN: Duration = Duration::from_secs(3600);
+
+static BAN_UNTIL: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// 检查返回的内容是否是封禁提示，是的话解析剩余时间
+/// 例如 "The ban expires in 1 hour and 3 minutes" / "2 days, 5 hours and 10 seconds"
+pub fn detect(body: &str) -> Option<Duration> {
+    if !body.contains(BAN_TEXT) {
+        return None;
+    }
+    Some(parse_expiry(body).unwrap_or(DEFAULT_BAN))
+}
+
+fn parse_expiry(body: &str) -> Option<Duration> {
+    let pos = body.find("expires in")?;
+    let text = &body[pos + "expires in".len()..];
+    let text = text.split('.').next().unwrap_or(text);
+
+    let mut secs = 0u64;
+    let mut found = false;
+    let words: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|w| !w.is_empty())
+        .collect();
+    for pair in words.windows(2) {
+        let n = match pair[0].parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let unit = match pair[1].trim_end_matches('s') {
+            "day" => 86400,
+            "hour" => 3600,
+            "minute" => 60,
+            "second" => 1,
+            _ => continue,
+        };
+        secs += n * unit;
+        found = true;
+    }
+
+    if found {
+        Some(Duration::from_secs(secs))
+    } else {
+        None
+    }
+}
+
+/// 记录封禁，封禁时间以最晚的为准
+pub fn start(duration: Duration) {
+    let until = Instant::now() + duration;
+    let mut lock = BAN_UNTIL.lock().unwrap();
+    if lock.is_none_or(|u| u < until) {
+        logger::error(&format!(
+            "Your IP has been temporarily banned, the ban expires in {}",
+            format_duration(duration)
+        ));
+        *lock = Some(until);
+    }
+}
+
+/// 清除封禁记录
+#[cfg(test)]
+pub fn reset() {
+    *BAN_UNTIL.lock().unwrap() = None;
+}
+
+/// 封禁的剩余时间，没有被封或者已经解封时返回None
+pub fn remaining() -> Option<Duration> {
+    let mut lock = BAN_UNTIL.lock().unwrap();
+    match *lock {
+        Some(until) if until > Instant::now() => Some(until - Instant::now()),
+        Some(_) => {
+            *lock = None;
+            None
+        }
+        None => None,
+    }
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}h {}m {}s", secs / 3600, secs % 3600 / 60, secs % 60)
+}
@@ -0,0 +1,32 @@
+use crate::retry::RetryPolicy;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// 配置文件(toml)中的通用设置，命令行参数的优先级更高
+/// watch 子命令保存的搜索也写在同一个文件中，见 watch::WatchConfig
+///
+/// ```toml
+/// [retry]
+/// max_attempts = 5
+/// base_ms = 500
+/// cap_ms = 10000
+/// jitter_ms = 300
+/// connect_attempts = 5
+/// timeout_attempts = 3
+/// server_attempts = 5
+/// throttled_attempts = 5
+/// retry_after_cap = 300
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+}
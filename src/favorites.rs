@@ -0,0 +1,235 @@
+// favorites 子命令：收藏夹的分类、添加/删除图集
+// 所有请求都基于 --base-url，测试时可以指向本地的模拟服务器
+
+use crate::handler::{DownloadError, Handler};
+use crate::source;
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::Class;
+
+pub const DEFAULT_BASE_URL: &str = "https://e-hentai.org";
+
+/// 收藏分类(favcat 0-9)
+#[derive(Debug, Clone)]
+pub struct Category {
+    pub index: u8,
+    pub name: String,
+    pub count: u32,
+}
+
+pub struct Favorites {
+    h: Handler,
+    base: Url,
+}
+
+impl Favorites {
+    pub fn new(base: &Url, cookie: &str) -> Self {
+        Favorites {
+            h: Handler::new(base.host_str().unwrap_or_default(), cookie),
+            base: base.clone(),
+        }
+    }
+
+    pub fn handler(&self) -> &Handler {
+        &self.h
+    }
+
+    /// 收藏夹列表页，index 为None时是所有分类
+    pub fn category_url(&self, index: Option<u8>) -> Url {
+        let path = match index {
+            Some(i) => format!("favorites.php?favcat={}", i),
+            None => "favorites.php".to_string(),
+        };
+        self.base.join(&path).expect("Incorrect base url")
+    }
+
+    /// 收藏夹页面顶部的分类列表：
+    /// <div class="fp" onclick="document.location='...favorites.php?favcat=0'"><div>123</div><div class="i"></div><div>Favorites 0</div></div>
+    /// 最后一个 "Show All Favorites" 没有 favcat，不在结果中
+    pub fn categories(&self) -> Result<Vec<Category>, DownloadError> {
+        let body = self.h.request("Get favorites", self.category_url(None).as_str())?;
+        let categories: Vec<Category> = Document::from(body.as_str())
+            .find(Class("fp"))
+            .filter_map(|fp| {
+                let onclick = fp.attr("onclick")?;
+                let start = onclick.find("favcat=")? + "favcat=".len();
+                let index = onclick[start..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>()
+                    .parse()
+                    .ok()?;
+                let texts: Vec<String> = fp.children().map(|n| n.text().trim().to_string()).filter(|t| !t.is_empty()).collect();
+                Some(Category {
+                    index,
+                    count: texts.first()?.replace(',', "").parse().unwrap_or(0),
+                    name: texts.last()?.clone(),
+                })
+            })
+            .collect();
+        if categories.is_empty() {
+            return Err(DownloadError::Parse(
+                "No favorite categories found, the cookie may be missing or expired".to_string(),
+            ));
+        }
+        Ok(categories)
+    }
+
+    /// 添加到分类，已经收藏的图集会移动到该分类并更新备注
+    pub fn add(&self, gallery: &Url, category: u8, note: &str) -> Result<(), DownloadError> {
+        let favcat = category.to_string();
+        self.popup(gallery, &[("favcat", &favcat), ("favnote", note), ("apply", "Add to Favorites"), ("update", "1")])
+    }
+
+    pub fn remove(&self, gallery: &Url) -> Result<(), DownloadError> {
+        self.popup(gallery, &[("favcat", "favdel"), ("favnote", ""), ("apply", "Apply Changes"), ("update", "1")])
+    }
+
+    // gallerypopups.php?gid=<id>&t=<token>&act=addfav
+    // 修改成功时返回的页面只有一段更新原页面并关闭弹窗的脚本；
+    // 没有登录或者参数错误时同样是200，但返回的是登录页或者收藏表单
+    fn popup(&self, gallery: &Url, form: &[(&str, &str)]) -> Result<(), DownloadError> {
+        let (gid, token) = gallery_key(gallery)
+            .ok_or_else(|| DownloadError::Parse(format!("Not a gallery url: {}", gallery)))?;
+        let url = self
+            .base
+            .join(&format!("gallerypopups.php?gid={}&t={}&act=addfav", gid, token))
+            .expect("Incorrect base url");
+        let body = self.h.post_form(url.as_str(), form)?;
+        match body.contains(POPUP_DONE) {
+            true => Ok(()),
+            false => Err(DownloadError::Parse(format!(
+                "Favorites of {} were not updated, the cookie may be missing or expired",
+                gallery
+            ))),
+        }
+    }
+}
+
+// 收藏修改成功后返回的脚本中关闭弹窗的语句
+const POPUP_DONE: &str = "window.close()";
+
+// 图集url(/g/<id>/<token>/)中的id和token
+fn gallery_key(url: &Url) -> Option<(u32, String)> {
+    let gid = source::gallery_id(url)?;
+    let token = url.path_segments()?.filter(|s| !s.is_empty()).nth(2)?.to_string();
+    Some((gid, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const GALLERY: &str = "https://e-hentai.org/g/3729116/c06ff2b95a/";
+
+    // 本地的模拟服务器：记录每个请求("<方法> <路径> <请求体>")，按路径返回页面
+    fn serve(page: fn(&str) -> &'static str) -> (Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                log.lock().unwrap().push(format!("{} {} {}", method, path, String::from_utf8_lossy(&body)));
+
+                let html = page(path);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    html.len(),
+                    html
+                );
+            }
+        });
+        (base, requests)
+    }
+
+    fn page(path: &str) -> &'static str {
+        match path {
+            "/favorites.php" => include_str!("../tests/fixtures/favorites.html"),
+            // 收藏修改成功
+            p if p.starts_with("/gallerypopups.php") => include_str!("../tests/fixtures/favorites_popup_done.html"),
+            _ => "",
+        }
+    }
+
+    #[test]
+    fn categories() {
+        let _globals = handler::lock_globals();
+        let (base, requests) = serve(page);
+        let categories = Favorites::new(&base, "").categories().unwrap();
+
+        let found: Vec<(u8, &str, u32)> = categories.iter().map(|c| (c.index, c.name.as_str(), c.count)).collect();
+        assert_eq!(found, [(0, "Favorites 0", 1234), (1, "To Read", 56), (9, "Favorites 9", 0)]);
+        assert_eq!(*requests.lock().unwrap(), ["GET /favorites.php "]);
+    }
+
+    #[test]
+    fn categories_without_login() {
+        let _globals = handler::lock_globals();
+        let (base, _) = serve(|_| "<html><body>Please log in</body></html>");
+        assert!(Favorites::new(&base, "").categories().is_err());
+    }
+
+    #[test]
+    fn add() {
+        let _globals = handler::lock_globals();
+        let (base, requests) = serve(page);
+        let gallery = Url::parse(GALLERY).unwrap();
+        Favorites::new(&base, "").add(&gallery, 1, "later").unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            ["POST /gallerypopups.php?gid=3729116&t=c06ff2b95a&act=addfav favcat=1&favnote=later&apply=Add+to+Favorites&update=1"]
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let _globals = handler::lock_globals();
+        let (base, requests) = serve(page);
+        let gallery = Url::parse(GALLERY).unwrap();
+        Favorites::new(&base, "").remove(&gallery).unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            ["POST /gallerypopups.php?gid=3729116&t=c06ff2b95a&act=addfav favcat=favdel&favnote=&apply=Apply+Changes&update=1"]
+        );
+    }
+
+    #[test]
+    fn popup_not_accepted() {
+        let _globals = handler::lock_globals();
+        let (base, _) = serve(|_| include_str!("../tests/fixtures/favorites_popup_form.html"));
+        let gallery = Url::parse(GALLERY).unwrap();
+        assert!(Favorites::new(&base, "").remove(&gallery).is_err());
+        assert!(Favorites::new(&base, "").add(&Url::parse("https://e-hentai.org/tag/x").unwrap(), 0, "").is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use reqwest::header::*;
 use std::path::Path;
 use std::fmt;
+use std::time::Duration;
 
 //kimi新增
 use reqwest::Proxy;
@@ -10,10 +11,20 @@ use std::collections::HashMap;
 // use std::time::Duration;
 // use reqwest::Url;
 
+#[cfg(not(test))]
 use clap::App;
 use crate::parser;
 use crate::parser::Cli;
 use crate::parser::ProxyMode;
+use crate::validate;
+use crate::ban;
+use crate::interrupt::{self, InFlight};
+use crate::report;
+use crate::logger;
+use crate::progress::{self, Counted};
+use crate::stall::{IdleReader, StallReader};
+use crate::retry::{self, ErrorClass};
+use crate::ratelimit::{RateLimiter, RequestKind};
 
 //用来实现同步单例
 use once_cell::sync::Lazy;
@@ -24,14 +35,38 @@ use once_cell::sync::Lazy;
 
 
 //创建全局的CLI参数解析对象
+#[cfg(not(test))]
 static GLOBAL_CLI: Lazy<Cli> = Lazy::new(|| {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
     let cli: Cli = parser::parse_cli(&matches);
-    // println!("CLI -> {:?}", cli);
-    return cli;
+    cli
 });
 
+// 单元测试不解析测试程序自己的参数，使用默认设置
+#[cfg(test)]
+static GLOBAL_CLI: Lazy<Cli> = Lazy::new(parser::defaults);
+
+
+// 全局限速器：所有线程的页面请求和图片请求共享
+static GLOBAL_LIMITER: Lazy<RateLimiter> = Lazy::new(|| {
+    let cli: &Cli = &GLOBAL_CLI;
+    RateLimiter::new(cli.rate, cli.image_rate, cli.host_rates.clone())
+});
+
+
+// 测试之间共享限速器、封禁和运行报告这些全局状态：
+// 用到它们的测试先拿到这个锁，拿到时状态已经重置，结果不受测试顺序影响
+#[cfg(test)]
+pub fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    GLOBAL_LIMITER.reset();
+    ban::reset();
+    report::reset();
+    guard
+}
+
 
 // 创建一个全局的GLOBAL_PROXIES对象-只初始化一次
 static GLOBAL_PROXIES: Lazy<Vec<Proxy>> = Lazy::new(|| {
@@ -41,27 +76,43 @@ static GLOBAL_PROXIES: Lazy<Vec<Proxy>> = Lazy::new(|| {
 
 //全局client+proxys
 static GLOBAL_CLIENT_PROXIES: Lazy<Client> = Lazy::new(|| {
-    let mut client = Client::builder()
-        .danger_accept_invalid_certs(true)          // 关闭TLS证书校验
-        .danger_accept_invalid_hostnames(true);
+    // 超时后按照 ErrorClass::Timeout 重试，避免卡住的节点一直占用线程
+    Handler::build_shared_client(Some(GLOBAL_CLI.timeout))
+});
 
 
-    //使用全局共享的proxys对象
-    // let proxies = GLOBAL_PROXIES.clone();
-    // for proxy in proxies {
-    //     client = client.proxy(proxy);
-    // }
+// 下载压缩包这样的大文件：没有总超时，卡住的连接由低速检测中断
+static GLOBAL_CLIENT_STREAM: Lazy<Client> = Lazy::new(|| Handler::build_shared_client(None));
 
 
-    for proxy in GLOBAL_PROXIES.iter() {
-        client = client.proxy(proxy.clone());
-    }
+impl Handler {
+    fn build_shared_client(timeout: Option<Duration>) -> Client {
+        let cli: &Cli = &GLOBAL_CLI;
+        let mut client = Client::builder()
+            .danger_accept_invalid_certs(true)          // 关闭TLS证书校验
+            .danger_accept_invalid_hostnames(true)
+            .connect_timeout(cli.connect_timeout)
+            .timeout(timeout);
 
-    client.build().unwrap()
-});
+
+        //使用全局共享的proxys对象
+        // let proxies = GLOBAL_PROXIES.clone();
+        // for proxy in proxies {
+        //     client = client.proxy(proxy);
+        // }
+
+
+        for proxy in GLOBAL_PROXIES.iter() {
+            client = client.proxy(proxy.clone());
+        }
+
+        client.build().unwrap()
+    }
+}
 
 
 // 创建Client的单利，防止重复配置
+#[allow(dead_code)]
 static GLOBAL_CLIENT: Lazy<Client> = Lazy::new(|| {
     // Client::builder()
     //     .pool_max_idle_per_host(16)
@@ -83,6 +134,25 @@ pub enum DownloadError {
     Request(reqwest::Error),
     Io(std::io::Error),
     Verification(String),
+    /// 文件的SHA-1和页面链接 /s/<hash>/ 中的不一致
+    HashMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    /// 页面内容不符合预期，例如图片页中找不到图片
+    Parse(String),
+    /// IP被临时封禁，值为剩余的封禁时间
+    Banned(Duration),
+    /// 图片配额用完(509)，在配额恢复之前不会再有图片可以下载
+    QuotaExceeded,
+    /// 按下了Ctrl-C，不再发送新的请求
+    Interrupted,
+    /// 服务器返回429/503，retry_after 为 Retry-After 头中要求等待的时间
+    Throttled {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
 }
 
 impl fmt::Display for DownloadError {
@@ -91,10 +161,24 @@ impl fmt::Display for DownloadError {
             DownloadError::Request(e) => write!(f, "Request error: {}", e),
             DownloadError::Io(e) => write!(f, "IO error: {}", e),
             DownloadError::Verification(msg) => write!(f, "Verification failed: {}", msg),
+            DownloadError::HashMismatch { file, expected, actual } => {
+                write!(f, "Verification failed: SHA-1 mismatch (expected {}, got {}): {}", expected, actual, file)
+            }
+            DownloadError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            DownloadError::Banned(d) => write!(f, "IP banned, the ban expires in {}", ban::format_duration(*d)),
+            DownloadError::QuotaExceeded => write!(f, "Image quota exceeded (509)"),
+            DownloadError::Interrupted => write!(f, "Interrupted"),
+            DownloadError::Throttled { status, retry_after: Some(d) } => {
+                write!(f, "Throttled ({}), retry after {}s", status, d.as_secs())
+            }
+            DownloadError::Throttled { status, retry_after: None } => write!(f, "Throttled ({})", status),
         }
     }
 }
 
+impl std::error::Error for DownloadError {}
+
+#[allow(dead_code)]
 impl DownloadError {
     pub fn is_timeout(&self) -> bool {
         match self {
@@ -190,7 +274,60 @@ impl DownloadError {
             // =========================
             // 文件校验失败
             // =========================
-            DownloadError::Verification(_) => false,
+            // 截断或损坏的内容通常是传输问题，重新下载即可
+            DownloadError::Verification(_) => true,
+
+            // 偶尔的SHA-1不匹配也可能是传输问题；每次都得到同样的内容时由调用者放弃
+            DownloadError::HashMismatch { .. } => true,
+
+            DownloadError::Parse(_) => false,
+
+            // 被封期间重试只会延长封禁时间
+            DownloadError::Banned(_) => false,
+
+            // 配额要很久才会恢复，重试只会浪费请求
+            DownloadError::QuotaExceeded => false,
+
+            DownloadError::Interrupted => false,
+
+            // 429/503 按照 Retry-After 等待后重试
+            DownloadError::Throttled { .. } => true,
+        }
+    }
+
+    /// 配额用完，同时记录下来用于最后的退出码
+    pub fn quota_exceeded() -> Self {
+        report::mark_quota_exceeded();
+        DownloadError::QuotaExceeded
+    }
+
+    /// 错误分类，决定使用哪个重试次数，也写入日志和运行报告
+    /// 不可重试的错误同样返回具体的原因
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            DownloadError::Request(e) if e.is_timeout() => ErrorClass::Timeout,
+            DownloadError::Request(e) if e.is_connect() => ErrorClass::Connect,
+            DownloadError::Request(e) => match e.status() {
+                Some(status) if status.is_server_error() => ErrorClass::Server,
+                Some(status) if status == reqwest::StatusCode::TOO_MANY_REQUESTS => ErrorClass::Throttled,
+                Some(_) => ErrorClass::Client,
+                None => ErrorClass::Other,
+            },
+            DownloadError::Throttled { .. } => ErrorClass::Throttled,
+            DownloadError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => ErrorClass::Timeout,
+            DownloadError::Io(_) => ErrorClass::Io,
+            DownloadError::Verification(_) | DownloadError::HashMismatch { .. } => ErrorClass::Verification,
+            DownloadError::Parse(_) => ErrorClass::Parse,
+            DownloadError::Banned(_) => ErrorClass::Banned,
+            DownloadError::QuotaExceeded => ErrorClass::Quota,
+            DownloadError::Interrupted => ErrorClass::Interrupted,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DownloadError::Throttled { retry_after, .. } => *retry_after,
+            _ => None,
         }
     }
 }
@@ -204,12 +341,15 @@ pub struct Handler {
     pub client: Client,
     pub host: String,
     cookie: String,
+    /// 限速使用的主机，通常和 host 相同
+    rate_host: String,
 }
 
 
 
 // 创建client+proxy；proxy对象每次都会重建
 // 该方法可以舍弃，推荐使用先获取需要的proxys对象，然后new client时，循环设置proxy
+#[allow(dead_code)]
 impl Handler {
 
     // 根据参数选择对应的代理配置
@@ -228,31 +368,27 @@ impl Handler {
 
         // 1️⃣ 最高优先级：--proxy
         if let Some(proxy_url) = &cli.proxy {//or &GLOBAL_CLI.proxy
-             println!("Using custom proxy: {}", proxy_url);
-             // println!("准备配置自定义代理服务: {}", proxy_url);
-            return Self::apply_custom_proxy(&proxy_url,&cli);
+             logger::info(&format!("Using custom proxy: {}", proxy_url));
+            Self::apply_custom_proxy(proxy_url,cli)
         } else {
             // 2️⃣ 根据 proxy-mode
             match cli.proxy_mode {
                 ProxyMode::None => { 
-                    println!("Proxy mode: none (no proxy)");
+                    logger::debug("Proxy mode: none (no proxy)");
                     // return Client::new();
 
-                    let mut client = Client::builder()
+                    Client::builder()
                         .danger_accept_invalid_certs(true)          // 关闭TLS证书校验
                         .danger_accept_invalid_hostnames(true)
-                        .build().unwrap();
-                    return client;               
+                        .build().unwrap()
                 }
                 ProxyMode::Http => { 
-                    println!("Proxy mode: http (env)");
-                    // println!("准备配置HTTP代理服务，从环境变量中获取代理地址......");
-                    return Self::apply_http_env_proxy();
+                    logger::info("Proxy mode: http (env)");
+                    Self::apply_http_env_proxy()
                 }
                 ProxyMode::Socks => { 
-                    println!("Proxy mode: socks (env)");
-                    // println!("准备配置SOCKS代理服务，从环境变量中获取代理地址......");
-                    return Self::apply_socks_env_proxy(&cli);
+                    logger::info("Proxy mode: socks (env)");
+                    Self::apply_socks_env_proxy(cli)
                 }
             }
         }
@@ -274,45 +410,33 @@ impl Handler {
         if let Ok(http_proxy) = std::env::var("http_proxy")
             .or_else(|_| std::env::var("HTTP_PROXY"))
         {
-            println!("HTTP proxy found: {}", http_proxy);
-             // println!("从环境中找到HTTP代理地址: {}", http_proxy);
+            logger::debug(&format!("HTTP proxy found: {}", http_proxy));
             if let Ok(proxy) = Proxy::http(&http_proxy) {
                 builder = builder.proxy(proxy);
-                // println!("HTTP代理，配置成功！");
-                println!("HTTP proxy, configuration successful!");
+                logger::debug("HTTP proxy, configuration successful!");
             }else{
-                // println!("HTTP代理，配置失败！");
-                println!("HTTP proxy, configuration failed!");
+                logger::warn("HTTP proxy, configuration failed!");
             }
         } else {
-            // println!("No HTTP proxy found in environment");
-            println!("No HTTP_PROXY|http_proxy found in environment");
-            println!("HTTP proxy, configuration failed!");
+            logger::warn("No HTTP_PROXY|http_proxy found in environment");
+            logger::warn("HTTP proxy, configuration failed!");
 
-            // println!("没有在环境中找到HTTP代理地址，环境变量名应该为：http_proxy 或 HTTP_PROXY");
-            // println!("HTTP代理，配置失败！");
         }
 
         if let Ok(https_proxy) = std::env::var("https_proxy")
             .or_else(|_| std::env::var("HTTPS_PROXY"))
         {
-            println!("HTTPS proxy found: {}", https_proxy);
-            // println!("从环境中找到HTTPS代理地址: {}", https_proxy);
+            logger::debug(&format!("HTTPS proxy found: {}", https_proxy));
             if let Ok(proxy) = Proxy::https(&https_proxy) {
                 builder = builder.proxy(proxy);
-                // println!("HTTPS代理，配置成功！");
-                println!("HTTPS proxy, configuration successful!");
+                logger::debug("HTTPS proxy, configuration successful!");
             }else{
-                // println!("HTTPS代理，配置失败！");
-                println!("HTTPS proxy, configuration failed!");
+                logger::warn("HTTPS proxy, configuration failed!");
             }
         } else {
-            // println!("No HTTPS proxy found in environment");
-            println!("No HTTPS_PROXY|https_proxy found in environment");
-            println!("HTTPS proxy, configuration failed!");
+            logger::warn("No HTTPS_PROXY|https_proxy found in environment");
+            logger::warn("HTTPS proxy, configuration failed!");
 
-             // println!("没有在环境中找到HTTP代理地址，环境变量名应该为：https_proxy 或 HTTPS_PROXY");
-             // println!("HTTPS代理，配置失败！");
         }
 
         builder.build().unwrap()
@@ -328,31 +452,23 @@ impl Handler {
         if let Ok(mut proxy_url) = std::env::var("all_proxy")
             .or_else(|_| std::env::var("ALL_PROXY"))
         {
-            println!("SOCKS proxy found: {}", proxy_url);
-            // println!("从环境中找到SOCKS代理地址: {}", proxy_url);
-
-            if cli.convert_socks5h {
-                if proxy_url.starts_with("socks5://") {
-                    proxy_url = proxy_url.replacen("socks5://", "socks5h://", 1);
-                    println!("Convert socks5 to socks5h: {}", proxy_url);
-                    // println!("已将socks5地址转换成socks5h地址：{}", proxy_url);
-                }
+            logger::debug(&format!("SOCKS proxy found: {}", proxy_url));
+
+            if cli.convert_socks5h && proxy_url.starts_with("socks5://") {
+                proxy_url = proxy_url.replacen("socks5://", "socks5h://", 1);
+                logger::debug(&format!("Convert socks5 to socks5h: {}", proxy_url));
             }
 
             if let Ok(proxy) = Proxy::all(&proxy_url) {
                 builder = builder.proxy(proxy);
-                // println!("SOCKS代理，配置成功!");
-                println!("SOCKS proxy, configuration successful!");
+                logger::debug("SOCKS proxy, configuration successful!");
             }else{
-                // println!("SOCKS代理，配置失败!");
-                println!("SOCKS proxy, configuration failed!");
+                logger::warn("SOCKS proxy, configuration failed!");
             }
         } else {
-            println!("No ALL_PROXY|all_proxy found in environment");
-            println!("SOCKS proxy, configuration failed!");
+            logger::warn("No ALL_PROXY|all_proxy found in environment");
+            logger::warn("SOCKS proxy, configuration failed!");
 
-            // println!("没有在环境中找到SOCKS代理地址，环境变量名应该为：all_proxy 或 ALL_PROXY");
-            // println!("SOCKS代理，配置失败!");
         }
 
         builder.build().unwrap()
@@ -366,55 +482,41 @@ impl Handler {
 
 
         if proxy_url.starts_with("http://") || proxy_url.starts_with("https://") {
-            // println!("Custom HTTP proxy");
-            println!("Configure HTTP/HTTPS proxy: {}", proxy_url);
-            // println!("准备配置HTTP/HTTPS代理......");
+            logger::debug(&format!("Configure HTTP/HTTPS proxy: {}", proxy_url));
 
             if let Ok(proxy) = Proxy::http(proxy_url) {
                 builder = builder.proxy(proxy);
-                // println!("HTTP代理，配置成功！");
-                println!("HTTP proxy, configuration successful!");
+                logger::debug("HTTP proxy, configuration successful!");
             }else{
-                // println!("HTTP代理，配置失败！");
-                println!("HTTP proxy, configuration failed!");
+                logger::warn("HTTP proxy, configuration failed!");
             }
 
             if let Ok(proxy) = Proxy::https(proxy_url) {
                 builder = builder.proxy(proxy);
-                // println!("HTTPS代理，配置成功！");
-                println!("HTTPS proxy, configuration successful!");
+                logger::debug("HTTPS proxy, configuration successful!");
             }else{
-                // println!("HTTPS代理，配置失败！");
-                println!("HTTPS proxy, configuration failed!");
+                logger::warn("HTTPS proxy, configuration failed!");
 
             }
 
         } else if proxy_url.starts_with("socks5://") || proxy_url.starts_with("socks5h://") {
             let mut url = proxy_url.to_string();
-            // println!("Custom SOCKS proxy: {}", url);
-            println!("Configure SOCKS proxy: {}", url);
-            // println!("准备配置SOCKS代理......");
+            logger::debug(&format!("Configure SOCKS proxy: {}", url));
 
-            if cli.convert_socks5h {
-                if url.starts_with("socks5://") {
-                    url = url.replacen("socks5://", "socks5h://", 1);
-                    println!("Convert socks5 to socks5h: {}", url);
-                    // println!("已将socks5地址转换成socks5h地址：{}", url);
-                }
+            if cli.convert_socks5h && url.starts_with("socks5://") {
+                url = url.replacen("socks5://", "socks5h://", 1);
+                logger::debug(&format!("Convert socks5 to socks5h: {}", url));
             }
 
 
             if let Ok(proxy) = Proxy::all(&url) {
                 builder = builder.proxy(proxy);
-                println!("SOCKS proxy, configuration successful!");
-                // println!("SOCKS代理，配置成功!");
+                logger::debug("SOCKS proxy, configuration successful!");
             }else{
-                println!("SOCKS proxy, configuration failed!");
-                // println!("SOCKS代理，配置失败!");
+                logger::warn("SOCKS proxy, configuration failed!");
             }
         } else {
-            println!("Unsupported proxy scheme: {}", proxy_url);
-            // println!("自定义代理配置失败，不支持的代理方案: {}", proxy_url);
+            logger::warn(&format!("Unsupported proxy scheme: {}", proxy_url));
         }
 
         builder.build().unwrap()
@@ -446,103 +548,99 @@ impl Handler {
 
         // 1️⃣ 最高优先级：--proxy
         if let Some(proxy_url) = &cli.proxy {////or &GLOBAL_CLI.proxy
-             println!("Using custom proxy: {}", proxy_url);
+             logger::info(&format!("Using custom proxy: {}", proxy_url));
              if proxy_url.starts_with("http://") || proxy_url.starts_with("https://") {
                 if let Ok(proxy) = Proxy::http(proxy_url) {
                      proxies.push(proxy);
-                    println!("HTTP proxy, configuration successful!");
+                    logger::debug("HTTP proxy, configuration successful!");
                 }else{
-                    println!("HTTP proxy, configuration failed!");
+                    logger::warn("HTTP proxy, configuration failed!");
                 }
                 if let Ok(proxy) = Proxy::https(proxy_url) {
                      proxies.push(proxy);
-                    println!("HTTPS proxy, configuration successful!");
+                    logger::debug("HTTPS proxy, configuration successful!");
                 }else{
-                    println!("HTTPS proxy, configuration failed!");
+                    logger::warn("HTTPS proxy, configuration failed!");
 
                 }
             }else if proxy_url.starts_with("socks5://") || proxy_url.starts_with("socks5h://") {
                 let mut url = proxy_url.to_string();
-                println!("Configure SOCKS proxy: {}", url);
-                if cli.convert_socks5h {
-                    if url.starts_with("socks5://") {
-                        url = url.replacen("socks5://", "socks5h://", 1);
-                        println!("Convert socks5 to socks5h: {}", url);
-                    }
+                logger::debug(&format!("Configure SOCKS proxy: {}", url));
+                if cli.convert_socks5h && url.starts_with("socks5://") {
+                    url = url.replacen("socks5://", "socks5h://", 1);
+                    logger::debug(&format!("Convert socks5 to socks5h: {}", url));
                 }
                 if let Ok(proxy) = Proxy::all(&url) {
                     proxies.push(proxy);
-                    println!("SOCKS proxy, configuration successful!");
+                    logger::debug("SOCKS proxy, configuration successful!");
                 }else{
-                    println!("SOCKS proxy, configuration failed!");
+                    logger::warn("SOCKS proxy, configuration failed!");
                 }
             }else {
-                println!("Unsupported proxy scheme: {}", proxy_url);
+                logger::warn(&format!("Unsupported proxy scheme: {}", proxy_url));
             }
         } else {
             // 2️⃣ 根据 proxy-mode
             match cli.proxy_mode {
                 ProxyMode::None => { 
-                    println!("Proxy mode: none (no proxy)");
+                    logger::debug("Proxy mode: none (no proxy)");
                 }
                 ProxyMode::Http => { 
-                    println!("Proxy mode: http (env)");
+                    logger::info("Proxy mode: http (env)");
 
                     if let Ok(http_proxy) = std::env::var("http_proxy")
                         .or_else(|_| std::env::var("HTTP_PROXY"))
                     {
-                        println!("HTTP proxy found: {}", http_proxy);
+                        logger::debug(&format!("HTTP proxy found: {}", http_proxy));
                         if let Ok(proxy) = Proxy::http(&http_proxy) {
                             proxies.push(proxy);
-                            println!("HTTP proxy, configuration successful!");
+                            logger::debug("HTTP proxy, configuration successful!");
                         }else{
-                            println!("HTTP proxy, configuration failed!");
+                            logger::warn("HTTP proxy, configuration failed!");
                         }
                     } else {
-                        println!("No HTTP_PROXY|http_proxy found in environment");
-                        println!("HTTP proxy, configuration failed!");
+                        logger::warn("No HTTP_PROXY|http_proxy found in environment");
+                        logger::warn("HTTP proxy, configuration failed!");
                     }
 
 
                     if let Ok(https_proxy) = std::env::var("https_proxy")
                         .or_else(|_| std::env::var("HTTPS_PROXY"))
                     {
-                        println!("HTTPS proxy found: {}", https_proxy);
+                        logger::debug(&format!("HTTPS proxy found: {}", https_proxy));
                         if let Ok(proxy) = Proxy::https(&https_proxy) {
                             proxies.push(proxy);
-                            println!("HTTPS proxy, configuration successful!");
+                            logger::debug("HTTPS proxy, configuration successful!");
                         }else{
-                            println!("HTTPS proxy, configuration failed!");
+                            logger::warn("HTTPS proxy, configuration failed!");
                         }
                     } else {
-                        println!("No HTTPS_PROXY|https_proxy found in environment");
-                        println!("HTTPS proxy, configuration failed!");
+                        logger::warn("No HTTPS_PROXY|https_proxy found in environment");
+                        logger::warn("HTTPS proxy, configuration failed!");
                     }
                 }
                 ProxyMode::Socks => { 
-                    println!("Proxy mode: socks (env)");
+                    logger::info("Proxy mode: socks (env)");
                     
                     if let Ok(mut proxy_url) = std::env::var("all_proxy")
                         .or_else(|_| std::env::var("ALL_PROXY"))
                     {
-                        println!("SOCKS proxy found: {}", proxy_url);
+                        logger::debug(&format!("SOCKS proxy found: {}", proxy_url));
 
-                        if cli.convert_socks5h {
-                            if proxy_url.starts_with("socks5://") {
-                                proxy_url = proxy_url.replacen("socks5://", "socks5h://", 1);
-                                println!("Convert socks5 to socks5h: {}", proxy_url);
-                            }
+                        if cli.convert_socks5h && proxy_url.starts_with("socks5://") {
+                            proxy_url = proxy_url.replacen("socks5://", "socks5h://", 1);
+                            logger::debug(&format!("Convert socks5 to socks5h: {}", proxy_url));
                         }
 
                         if let Ok(proxy) = Proxy::all(&proxy_url) {
                             proxies.push(proxy);
-                            println!("SOCKS proxy, configuration successful!");
+                            logger::debug("SOCKS proxy, configuration successful!");
                         }else{
-                            println!("SOCKS proxy, configuration failed!");
+                            logger::warn("SOCKS proxy, configuration failed!");
                         }
                     } else {
-                        println!("No ALL_PROXY|all_proxy found in environment");
-                        println!("SOCKS proxy, configuration failed!");
+                        logger::warn("No ALL_PROXY|all_proxy found in environment");
+                        logger::warn("SOCKS proxy, configuration failed!");
                     }
                 }
             }
@@ -559,6 +657,7 @@ impl Handler {
 
 
 // 不同的方式创建client
+#[allow(dead_code)]
 impl Handler {
 
     // 方式1：
@@ -620,9 +719,16 @@ impl Handler {
             client: Self::build_client_proxies_global(),
             host: host.to_string(),
             cookie: cookie.to_string(),
+            rate_host: host.to_string(),
         }
     }
 
+    /// 按照另一个主机的限速发送请求(例如MPV的 api.e-hentai.org 使用图集所在主机的限速)
+    pub fn limited_as(mut self, host: &str) -> Self {
+        self.rate_host = host.to_string();
+        self
+    }
+
 
     //kimi新增 
     fn build_cookie(&self) -> String {
@@ -658,7 +764,8 @@ impl Handler {
     }
 
 
-    pub fn request(&self, _task: &str, url: &str) -> Result<reqwest::blocking::Response, reqwest::Error> {
+    /// 请求HTML页面并返回页面内容，被封IP时返回 DownloadError::Banned
+    pub fn request(&self, _task: &str, url: &str) -> Result<String, DownloadError> {
         // let res = self
         //     .client
         //     .get(url)
@@ -677,29 +784,213 @@ impl Handler {
         // .header("Accept", "image/webp,image/*,*/*")
 
 
+        self.fetch_text(|| self.client.get(url))
+    }
+
+    /// 调用 api.php，请求和返回都是JSON
+    pub fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<serde_json::Value, DownloadError> {
+        let text = self.fetch_text(|| {
+            self.client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+        })?;
+        serde_json::from_str(&text)
+            .map_err(|e| DownloadError::Parse(format!("Invalid API response from {}: {}", url, e)))
+    }
+
+    /// 提交表单(例如 gallerypopups.php 的收藏操作)，返回页面内容
+    pub fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<String, DownloadError> {
+        self.fetch_text(|| self.client.post(url).form(form))
+    }
+
+    // 发送页面请求并读取返回的文本，被封时等待或者返回 Banned
+    // 429/503、超时等可重试的错误按 RetryPolicy 的次数重试，优先使用 Retry-After
+    fn fetch_text<F>(&self, build: F) -> Result<String, DownloadError>
+    where
+        F: Fn() -> reqwest::blocking::RequestBuilder,
+    {
+        let policy = &GLOBAL_CLI.retry_policy;
+        let mut attempt = 1;
+        loop {
+            match self.fetch_text_once(&build) {
+                Err(e) if e.is_retryable() && attempt < policy.budget(e.class()) => {
+                    let delay = policy.delay(attempt, e.retry_after());
+                    logger::warn(&format!("Request failed ({}), retry in {:.1}s", e, delay.as_secs_f64()));
+                    interrupt::sleep(delay);
+                    if interrupt::requested() {
+                        return Err(DownloadError::Interrupted);
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn fetch_text_once<F>(&self, build: &F) -> Result<String, DownloadError>
+    where
+        F: Fn() -> reqwest::blocking::RequestBuilder,
+    {
+        loop {
+            let res = self.send_request(RequestKind::Page, build())?;
+            let status = Self::check_status(&res).err();
+            let body = res.text().map_err(DownloadError::Request)?;
+
+            // 封禁提示可能是200也可能是503，所以要在判断状态码之前检查
+            if let Some(duration) = ban::detect(&body) {
+                ban::start(duration);
+                continue;
+            }
+
+            // 让 HTTP 非 2xx 成为错误，如果没该方法，那么形如404也会返回为成功
+            return match status {
+                Some(e) => Err(e),
+                None => Ok(body),
+            };
+        }
+    }
+
+    /// 请求图片文件，与HTML页面使用不同的限速
+    pub fn request_image(&self, url: &str) -> Result<reqwest::blocking::Response, DownloadError> {
+        self.request_binary(|| self.client.get(url))
+    }
+
+    /// 下载压缩包这样的大文件到 dest：不受 --timeout 限制，由低速检测中断卡住的连接
+    /// 失败时删除 dest，返回写入的字节数
+    pub fn save_large(&self, url: &str, dest: &Path) -> Result<u64, DownloadError> {
+        let res = self.request_binary(|| GLOBAL_CLIENT_STREAM.get(url))?;
+        Self::save_body(res, dest)
+    }
+
+    // 二进制请求：识别封禁提示、错误状态码和 509.gif
+    fn request_binary<F>(&self, build: F) -> Result<reqwest::blocking::Response, DownloadError>
+    where
+        F: Fn() -> reqwest::blocking::RequestBuilder,
+    {
+        loop {
+            let res = self.send_request(RequestKind::Image, build())?;
+
+            // 图片请求返回了文本，可能是封禁提示
+            let is_text = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("text/"));
+            if !is_text {
+                Self::check_status(&res)?;
+                // 配额用完时图片地址会被重定向到 509.gif
+                if res.url().path().ends_with("/509.gif") {
+                    return Err(DownloadError::quota_exceeded());
+                }
+                return Ok(res);
+            }
+
+            let status = Self::check_status(&res).err();
+            let body = res.text().map_err(DownloadError::Request)?;
+            if let Some(duration) = ban::detect(&body) {
+                ban::start(duration);
+                continue;
+            }
+            return Err(match status {
+                Some(e) => e,
+                None => DownloadError::Verification(format!(
+                    "Expected an image but got text: {}",
+                    body.chars().take(100).collect::<String>().trim()
+                )),
+            });
+        }
+    }
+
+    // 非2xx的状态码转换成错误，429/503 带上 Retry-After
+    fn check_status(res: &reqwest::blocking::Response) -> Result<(), DownloadError> {
+        let status = res.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(retry::parse_retry_after);
+            return Err(DownloadError::Throttled {
+                status: status.as_u16(),
+                retry_after,
+            });
+        }
+        res.error_for_status_ref().map(|_| ()).map_err(DownloadError::Request)
+    }
+
+    /// 封禁期间：指定了 --wait-on-ban 时等待解封，否则直接返回 Banned
+    pub fn check_ban() -> Result<(), DownloadError> {
+        while let Some(remaining) = ban::remaining() {
+            if !GLOBAL_CLI.wait_on_ban {
+                return Err(DownloadError::Banned(remaining));
+            }
+            interrupt::sleep(remaining.min(Duration::from_secs(60)));
+            if interrupt::requested() {
+                return Err(DownloadError::Interrupted);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_request(
+        &self,
+        kind: RequestKind,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::blocking::Response, DownloadError> {
+        if interrupt::requested() {
+            return Err(DownloadError::Interrupted);
+        }
+        Self::check_ban()?;
+
+        // 所有线程共享同一个限速器，先拿到令牌再发请求
+        let bucket = GLOBAL_LIMITER.bucket(kind, &self.rate_host);
+        bucket.acquire();
+
         //kimi修改 - 与 is_retryable 相关联
-        let res = self
-            .client
-            .get(url)
+        let res = request
             .header(COOKIE, self.build_cookie())
             .header(HOST, &self.host[..])
             .header(
                 USER_AGENT,
                 "Mozilla/5.0 (X11; Linux x86_64; rv:65.0) Gecko/20100101 Firefox/65.0",
             )
-            .send()?                // 网络错误
-            .error_for_status();    // 让 HTTP 非 2xx 成为错误，如果没该方法，那么形如404也会返回为成功
-        res
+            .send()                 // 网络错误
+            .map_err(DownloadError::Request)?;
+
+        // 429/503 说明请求太快了，自动降低这个主机的请求速度
+        let status = res.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            bucket.penalize();
+            logger::warn(&format!(
+                "{} returned {}, slow down {:?} requests to {:.2}/s",
+                self.host,
+                status.as_u16(),
+                kind,
+                bucket.current_rate()
+            ));
+        } else {
+            bucket.reward();
+        }
+
+        Ok(res)
     }
 
-    pub fn download(target: &str, path: &str, filename: &str, cookie: &str) -> Result<(), DownloadError> {
+    /// hash 为页面链接 /s/<hash>/ 中图片SHA-1的前10位，提供时会校验下载的内容
+    pub fn download(target: &str, path: &str, filename: &str, hash: Option<&str>, cookie: &str) -> Result<(), DownloadError> {
         //Kimi 新增 - 检查文件是否存在-如果存在则跳过本次下载
         let fname = Path::new(path).join(filename);
 
         // 🔴 第一步：检查是否已存在
+        // 已存在的文件和新下载的文件做同样的校验，之前运行留下的空文件、截断的文件、509占位图会重新下载
         if fname.exists() {
-            println!("File already exists: {}, skipping download.", fname.display());
-            return Ok(());
+            if GLOBAL_CLI.force {
+                // 先删除再下载：文件可能和其他图集硬链接，直接覆盖会把那边的文件也改掉
+                std::fs::remove_file(&fname).map_err(DownloadError::Io)?;
+            } else if Self::keep_existing(&fname, hash, GLOBAL_CLI.deep_verify)? {
+                progress::skipped(&fname.display().to_string());
+                return Ok(());
+            }
         }
 
 
@@ -710,32 +1001,108 @@ impl Handler {
             .unwrap_or_default();
 
         let dh = Handler::new(&host, cookie);
-        let mut res = match dh.request("Download", &target) {
-            Ok(r) => r,
-            Err(e) => return Err(DownloadError::Request(e)),
+        let started = std::time::Instant::now();
+        progress::started(&fname.display().to_string());
+        let result = {
+            let _guard = InFlight::new(&fname);
+            Self::fetch_image(&dh, target, &fname, hash)
         };
+        match result {
+            Ok(size) => progress::completed(&fname.display().to_string(), size, started.elapsed()),
+            Err(_) => progress::aborted(),
+        }
+        result.map(|_| ())
+    }
 
-        // 修改前
-        // let fname = Path::new(path).join(filename);
-        let mut dest = match std::fs::File::create(&fname) {
+    // 下载到 fname 并校验，返回文件大小
+    fn fetch_image(dh: &Handler, target: &str, fname: &Path, hash: Option<&str>) -> Result<u64, DownloadError> {
+        let res = dh.request_image(target)?;
+        Self::save_body(res, fname)?;
+        Self::verify_download(fname, hash)
+    }
+
+    // 把响应写入 fname 并和 Content-Length 比较，失败时删除半文件
+    fn save_body(res: reqwest::blocking::Response, fname: &Path) -> Result<u64, DownloadError> {
+        let mut dest = match std::fs::File::create(fname) {
             Ok(f) => f,
             Err(e) => return Err(DownloadError::Io(e)),
         };
 
-        println!("Downloading: {}", fname.to_str().unwrap());
-        if let Err(e) = std::io::copy(&mut res, &mut dest) {
-            //当 copy 失败时，必须删除半文件
-            let _ = std::fs::remove_file(&fname);
-            return Err(DownloadError::Io(e));
+        let expected_len = res.content_length();
+        // 节点完全不再发送数据时，IdleReader 在 --stall-time 之后放弃，不用等总超时
+        let idle = IdleReader::new(Counted(res), GLOBAL_CLI.stall_time);
+        let mut reader = StallReader::new(idle, GLOBAL_CLI.stall_speed, GLOBAL_CLI.stall_time);
+
+        let written = match std::io::copy(&mut reader, &mut dest) {
+            Ok(n) => n,
+            Err(e) => {
+                //当 copy 失败时，必须删除半文件
+                let _ = std::fs::remove_file(fname);
+                return Err(DownloadError::Io(e));
+            }
+        };
+
+        // 连接提前关闭时copy也可能"成功"，需要和Content-Length比较
+        if let Some(expected) = expected_len {
+            if written != expected {
+                let _ = std::fs::remove_file(fname);
+                return Err(DownloadError::Verification(format!(
+                    "Incomplete transfer ({} of {} bytes): {}",
+                    written,
+                    expected,
+                    fname.display()
+                )));
+            }
         }
+        Ok(written)
+    }
 
-        // Verify download: check file exists and has content
-        Self::verify_download(&fname)?;
+    // 已经存在的文件：校验通过时返回true(跳过下载)，否则移动到 invalid/ 并返回false
+    // hash 只在服务器提供的是原图时才会传入，缩小过的图片只做结构检查
+    fn keep_existing(fname: &Path, hash: Option<&str>, deep: bool) -> Result<bool, DownloadError> {
+        match Self::check_file(fname, hash, deep) {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let moved = Self::move_aside(fname).map_err(DownloadError::Io)?;
+                logger::warn(&format!(
+                    "Invalid existing file {} ({}), moved to {} and download again",
+                    fname.display(),
+                    e,
+                    moved.display()
+                ));
+                Ok(false)
+            }
+        }
+    }
 
-        Ok(())
+    // 把无效的文件移动到图集目录下的 invalid/ 中，保留下来方便检查
+    fn move_aside(path: &Path) -> std::io::Result<std::path::PathBuf> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(".")).join("invalid");
+        std::fs::create_dir_all(&dir)?;
+        let dest = dir.join(path.file_name().unwrap_or_default());
+        std::fs::rename(path, &dest)?;
+        Ok(dest)
+    }
+
+    fn verify_download(path: &Path, hash: Option<&str>) -> Result<u64, DownloadError> {
+        match Self::verify_file(path, hash) {
+            Ok(size) => Ok(size),
+            Err(e) => {
+                // 校验失败的文件必须删除，否则下次会被当成已下载而跳过
+                let _ = std::fs::remove_file(path);
+                Err(e)
+            }
+        }
     }
 
-    fn verify_download(path: &Path) -> Result<(), DownloadError> {
+    /// 检查文件：存在、大小、图片格式(--deep-verify 时检查完整结构)，提供hash时再校验SHA-1前缀
+    /// hash 是原图的SHA-1，服务器提供的是缩小过的图片时不能传入
+    /// 只做检查不修改文件，返回文件大小
+    pub fn verify_file(path: &Path, hash: Option<&str>) -> Result<u64, DownloadError> {
+        Self::check_file(path, hash, GLOBAL_CLI.deep_verify)
+    }
+
+    fn check_file(path: &Path, hash: Option<&str>, deep: bool) -> Result<u64, DownloadError> {
         // Check file exists
         if !path.exists() {
             return Err(DownloadError::Verification(format!(
@@ -748,7 +1115,6 @@ impl Handler {
         let metadata = std::fs::metadata(path).map_err(DownloadError::Io)?;
         let min_size = 1024; // 1KB minimum
         if metadata.len() < min_size {
-            let _ = std::fs::remove_file(path);
             return Err(DownloadError::Verification(format!(
                 "File too small ({} bytes, min {} bytes): {}",
                 metadata.len(),
@@ -759,15 +1125,53 @@ impl Handler {
 
         // Verify image magic bytes
         if !Self::is_valid_image(path)? {
-            let _ = std::fs::remove_file(path);
             return Err(DownloadError::Verification(format!(
                 "File is not a valid image: {}",
                 path.display()
             )));
         }
 
-        println!("Verified: {} ({} bytes)", path.display(), metadata.len());
-        Ok(())
+        // Verify image structure (--deep-verify)
+        if deep {
+            let data = std::fs::read(path).map_err(DownloadError::Io)?;
+            if let Err(reason) = validate::check_structure(&data) {
+                return Err(DownloadError::Verification(format!(
+                    "{}: {}",
+                    reason,
+                    path.display()
+                )));
+            }
+        }
+
+        // Verify SHA-1 prefix
+        if let Some(expected) = hash {
+            let actual = Self::sha1_hex(path)?;
+            if !actual.starts_with(&expected.to_lowercase()) {
+                return Err(DownloadError::HashMismatch {
+                    file: path.display().to_string(),
+                    expected: expected.to_string(),
+                    actual: actual[..expected.len().min(actual.len())].to_string(),
+                });
+            }
+        }
+
+        Ok(metadata.len())
+    }
+
+    fn sha1_hex(path: &Path) -> Result<String, DownloadError> {
+        use std::io::Read;
+
+        let mut file = std::fs::File::open(path).map_err(DownloadError::Io)?;
+        let mut sha1 = sha1_smol::Sha1::new();
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).map_err(DownloadError::Io)?;
+            if n == 0 {
+                break;
+            }
+            sha1.update(&buf[..n]);
+        }
+        Ok(sha1.digest().to_string())
     }
 
     fn is_valid_image(path: &Path) -> Result<bool, DownloadError> {
@@ -792,3 +1196,67 @@ impl Handler {
         Ok(is_jpeg || is_png || is_gif || is_webp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manga;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // 能通过结构检查的JPEG：SOI + 填充数据 + EOI
+    fn jpeg(fill: u8) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0];
+        data.extend(std::iter::repeat_n(fill, 2048));
+        data.extend([0xFF, 0xD9]);
+        data
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hentai-downloader-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn second_run_over_complete_gallery_moves_nothing() {
+        let dir = temp_dir("second-run");
+
+        // 原图：链接中的hash就是文件的SHA-1前缀
+        let original = dir.join("3729116-1.jpg");
+        fs::write(&original, jpeg(0x11)).unwrap();
+        let sha = Handler::sha1_hex(&original).unwrap();
+        let link = format!("https://e-hentai.org/s/{}/3729116-1", &sha[..10]);
+        let page = manga::page_from_image(&link, "https://h.example/a.jpg".to_string(), true).unwrap();
+        assert!(page.hash.is_some());
+        assert!(Handler::keep_existing(&original, page.hash.as_deref(), true).unwrap());
+
+        // 缩小过的图片：链接中的hash是原图的，和本地文件对不上
+        let resampled = dir.join("3729116-2.jpg");
+        fs::write(&resampled, jpeg(0x22)).unwrap();
+        let link = "https://e-hentai.org/s/0123456789/3729116-2";
+        let page = manga::page_from_image(link, "https://h.example/b.jpg".to_string(), false).unwrap();
+        assert_eq!(page.hash, None);
+        assert!(Handler::keep_existing(&resampled, page.hash.as_deref(), true).unwrap());
+
+        assert!(original.exists());
+        assert!(resampled.exists());
+        assert!(!dir.join("invalid").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_existing_file_is_moved_aside() {
+        let dir = temp_dir("truncated");
+        let file = dir.join("3729116-3.jpg");
+        let mut data = jpeg(0x33);
+        data.truncate(1500);
+        fs::write(&file, data).unwrap();
+
+        assert!(!Handler::keep_existing(&file, None, true).unwrap());
+        assert!(!file.exists());
+        assert!(dir.join("invalid").join("3729116-3.jpg").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+// Ctrl-C：第一次按下时不再开始新的请求，正在下载的文件下载完，保存进度后退出
+// 第二次按下时删除还没下载完的文件并立即退出
+
+use crate::handler::DownloadError;
+use crate::logger;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// 正在写入的文件，强制退出时删除，避免下次被当成已下载
+static IN_FLIGHT: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// 强制退出时的退出码(128 + SIGINT)
+pub const EXIT_CODE: i32 = 130;
+
+pub fn install() {
+    let result = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            for path in IN_FLIGHT.lock().unwrap().iter() {
+                let _ = std::fs::remove_file(path);
+            }
+            eprintln!("\nForce quit, partial files removed.");
+            std::process::exit(EXIT_CODE);
+        }
+        logger::warn("Interrupted: finishing the downloads in progress, press Ctrl-C again to force quit.");
+    });
+    if let Err(e) = result {
+        logger::warn(&format!("Install Ctrl-C handler failed: {}", e));
+    }
+}
+
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// 按下Ctrl-C后返回 DownloadError::Interrupted，用于在图集之间停止
+pub fn check() -> Result<(), DownloadError> {
+    if requested() {
+        Err(DownloadError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// 和 thread::sleep 相同，但是按下Ctrl-C后立即返回
+pub fn sleep(duration: Duration) {
+    let until = Instant::now() + duration;
+    while !requested() {
+        let now = Instant::now();
+        if now >= until {
+            break;
+        }
+        thread::sleep((until - now).min(Duration::from_millis(200)));
+    }
+}
+
+/// 标记文件正在写入，返回的guard被drop时取消标记
+pub struct InFlight(PathBuf);
+
+impl InFlight {
+    pub fn new(path: &Path) -> Self {
+        IN_FLIGHT.lock().unwrap().insert(path.to_path_buf());
+        InFlight(path.to_path_buf())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
@@ -0,0 +1,183 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 本地图集库中的一条记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u32,
+    pub token: String,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub pages: usize,
+    /// 图集地址，用于检查新版本
+    #[serde(default)]
+    pub url: String,
+    pub path: String,
+    /// 每个页面的文件名(不含扩展名) -> 图片hash(/s/<hash>/，SHA-1的前10位)
+    #[serde(default)]
+    pub hashes: BTreeMap<String, String>,
+    /// 下载时服务器提供的是原图、SHA-1已经校验过的页面
+    /// 其他页面是缩小过的图片(或者来源不明)，hash对不上是正常的，verify 时只检查结构
+    #[serde(default)]
+    pub originals: BTreeSet<String>,
+    /// 下载时间(unix秒)
+    pub downloaded_at: u64,
+    /// 所有页面是否都下载成功
+    pub complete: bool,
+    /// 配额用完时改为下载的原图压缩包：目录中的页面不完整，但压缩包中有所有页面
+    #[serde(default)]
+    pub archive: Option<String>,
+}
+
+impl Entry {
+    /// 旧的记录中没有保存url时，按照e-hentai.org的地址格式还原
+    pub fn gallery_url(&self) -> Url {
+        Url::parse(&self.url)
+            .or_else(|_| Url::parse(&format!("https://e-hentai.org/g/{}/{}/", self.id, self.token)))
+            .expect("Incorrect gallery url")
+    }
+
+    /// 记录完整(或者有压缩包)且目录仍然存在时，才认为不需要重新下载
+    pub fn is_available(&self) -> bool {
+        (self.complete || self.archive_file().is_some()) && Path::new(&self.path).is_dir()
+    }
+
+    /// 仍然存在的压缩包
+    pub fn archive_file(&self) -> Option<&Path> {
+        self.archive.as_deref().map(Path::new).filter(|p| p.is_file())
+    }
+
+    /// 所有关键字都要命中标题或者某个标签(不区分大小写)
+    pub fn matches(&self, terms: &[String]) -> bool {
+        let title = self.title.to_lowercase();
+        terms.iter().all(|t| {
+            let t = t.to_lowercase();
+            title.contains(&t) || self.tags.iter().any(|tag| tag.to_lowercase().contains(&t))
+        })
+    }
+}
+
+/// 已下载图集的索引，以json文件保存
+pub struct Library {
+    path: PathBuf,
+    entries: BTreeMap<u32, Entry>,
+    /// 图片hash -> [(图集id, 文件名)]，用于在不同图集之间复用相同的图片
+    hash_index: HashMap<String, Vec<(u32, String)>>,
+}
+
+impl Library {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut library = Library {
+            path: path.to_path_buf(),
+            entries: BTreeMap::new(),
+            hash_index: HashMap::new(),
+        };
+        if path.exists() {
+            let text = fs::read_to_string(path)?;
+            let list: Vec<Entry> = serde_json::from_str(&text)
+                .map_err(|e| format!("Broken library file {}: {}", path.display(), e))?;
+            for entry in list {
+                library.insert(entry);
+            }
+        }
+        Ok(library)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Entry> {
+        self.entries.get(&id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    pub fn query<'a>(&'a self, terms: &'a [String]) -> impl Iterator<Item = &'a Entry> {
+        self.entries().filter(move |e| e.matches(terms))
+    }
+
+    /// 查找本地已经下载过的、hash相同的图片文件(可能在多个图集中)
+    pub fn find_hash(&self, hash: &str) -> Vec<PathBuf> {
+        self.hash_index
+            .get(hash)
+            .into_iter()
+            .flatten()
+            .filter_map(|(id, stem)| {
+                let entry = self.entries.get(id)?;
+                find_local(Path::new(&entry.path), stem)
+            })
+            .collect()
+    }
+
+    /// 插入或更新一条记录(下载时间记为当前时间)并立即写盘，防止中途退出丢失记录
+    pub fn upsert(&mut self, mut entry: Entry) -> std::io::Result<()> {
+        entry.downloaded_at = now();
+        self.insert(entry);
+        self.save()
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        for refs in self.hash_index.values_mut() {
+            refs.retain(|(id, _)| *id != entry.id);
+        }
+        for (stem, hash) in &entry.hashes {
+            self.hash_index
+                .entry(hash.clone())
+                .or_default()
+                .push((entry.id, stem.clone()));
+        }
+        self.entries.insert(entry.id, entry);
+    }
+
+    // 先写临时文件再重命名，避免写到一半时索引文件损坏
+    fn save(&self) -> std::io::Result<()> {
+        let list: Vec<&Entry> = self.entries.values().collect();
+        let text = serde_json::to_string_pretty(&list)?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// 在目录中查找文件名(不含扩展名)为stem的文件
+pub fn find_local(dir: &Path, stem: &str) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.file_stem().and_then(|s| s.to_str()) == Some(stem))
+}
+
+/// 优先使用硬链接，不支持时(例如跨分区)退回到复制
+pub fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// unix秒转换成 YYYY-MM-DD (UTC)
+pub fn format_date(secs: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,153 @@
+// 日志：所有模块的输出都经过这里，按照 -v/-q 过滤
+// text 格式输出给人看(和进度条配合)；json 格式每条记录一行，输出到 stderr，供脚本解析
+
+use crate::progress;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// 默认 Info，每个 -v 提高一级，每个 -q 降低一级
+    pub fn from_verbosity(verbose: u64, quiet: u64) -> Self {
+        match 2 + verbose as i64 - quiet as i64 {
+            i64::MIN..=0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    /// --log-file：追加写入，格式和控制台相同，text格式额外带上时间和级别
+    file: Option<Mutex<File>>,
+}
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+/// 在输出任何日志前调用一次；没有调用时按照 Info + text 输出
+pub fn init(level: Level, format: Format, file: Option<&Path>) -> io::Result<()> {
+    let file = match file {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+
+    // json 输出和 -q 时不显示进度条
+    if format == Format::Json || level < Level::Info {
+        progress::disable();
+    }
+
+    let _ = LOGGER.set(Logger { level, format, file });
+    Ok(())
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger {
+        level: Level::Info,
+        format: Format::Text,
+        file: None,
+    })
+}
+
+pub fn error(msg: &str) {
+    write(Level::Error, None, msg, Map::new(), false);
+}
+
+pub fn warn(msg: &str) {
+    write(Level::Warn, None, msg, Map::new(), false);
+}
+
+pub fn info(msg: &str) {
+    write(Level::Info, None, msg, Map::new(), false);
+}
+
+pub fn debug(msg: &str) {
+    write(Level::Debug, None, msg, Map::new(), false);
+}
+
+/// 页面事件：resolved / downloading / verified / skipped / retry / failed
+/// fields 会原样出现在json记录中，text格式只输出msg
+pub fn event(level: Level, name: &str, msg: &str, fields: Value) {
+    let fields = match fields {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    // 终端中进度条已经显示了解析和下载中的页面数量，不再逐条输出
+    let detail = name == "resolved" || name == "downloading";
+    write(level, Some(name), msg, fields, detail);
+}
+
+fn write(level: Level, event: Option<&str>, msg: &str, fields: Map<String, Value>, detail: bool) {
+    let logger = logger();
+    if level > logger.level {
+        return;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let json_line = || {
+        let mut record = Map::new();
+        record.insert("ts".to_string(), json!(now.as_millis() as u64));
+        record.insert("level".to_string(), json!(level.as_str()));
+        if let Some(event) = event {
+            record.insert("event".to_string(), json!(event));
+        }
+        record.insert("msg".to_string(), json!(msg));
+        record.extend(fields.clone());
+        Value::Object(record).to_string()
+    };
+
+    if let Some(file) = &logger.file {
+        let line = match logger.format {
+            Format::Json => json_line(),
+            Format::Text => format!(
+                "{} {:02}:{:02}:{:02} {:5} {}",
+                crate::library::format_date(now.as_secs()),
+                now.as_secs() % 86400 / 3600,
+                now.as_secs() % 3600 / 60,
+                now.as_secs() % 60,
+                level.as_str().to_uppercase(),
+                msg
+            ),
+        };
+        let _ = writeln!(file.lock().unwrap(), "{}", line);
+    }
+
+    match logger.format {
+        Format::Json => progress::eprintln(&json_line()),
+        Format::Text if level <= Level::Warn => progress::eprintln(msg),
+        Format::Text if detail => progress::detail(msg),
+        Format::Text => progress::println(msg),
+    }
+}
@@ -2,106 +2,1042 @@
 extern crate clap;
 extern crate reqwest;
 
+mod archive;
+mod ban;
+mod config;
+mod favorites;
 mod handler;
+mod interrupt;
+mod library;
+mod logger;
 mod manga;
+mod metadata;
+mod mpv;
+mod pages;
+mod preview;
 mod parser;
+mod progress;
+mod quota;
+mod ratelimit;
+mod report;
+mod retry;
+mod source;
+mod stall;
+mod torrent;
+mod validate;
+mod watch;
 
+use crate::logger::Level;
 use crate::parser::Cli;
+use crate::mpv::{Mpv, Resolver};
+use crate::progress::{Failure, Stats};
+use crate::quota::QuotaStrategy;
+use crate::retry::ErrorClass;
+use crate::report::{ExitCode, GalleryReport, Status};
 
 use clap::App;
-use handler::Handler;
-use manga::Manga;
+use handler::{DownloadError, Handler};
+use manga::{Manga, Page, Thumbnail};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use threadpool::ThreadPool;
+use favorites::Favorites;
+use library::{Entry, Library};
+use watch::WatchConfig;
 
 
 //kimi 新增
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 
 
 
-//kimi - 实现指数退避（Exponential Backoff）
-fn jitter() -> u64 {
-    let nanos = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .subsec_nanos();
-
-    (nanos % 300) as u64
+/// 单个图集的下载结果
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// 没有找到可下载的资源
+    Unavailable,
+    /// 访问exhentai.org需要登录
+    AuthRequired,
+    /// 部分页面下载失败
+    Partial,
+    /// 所有页面下载成功
+    Complete,
+    /// 配额用完，下载了压缩包
+    Archived,
 }
 
-fn compute_backoff(attempt: u32) -> Duration {
-    let base: u64 = 500;       // 500ms 起始
-    let max_delay: u64 = 10_000; // 最大 10s
-
-    // 2^attempt，但最多放大到 2^5
-    let exp = base * 2_u64.pow(attempt.min(5));
-
-    let delay = (exp + jitter()).min(max_delay);
-
-    Duration::from_millis(delay)
+impl Outcome {
+    fn status(&self) -> Status {
+        match self {
+            Outcome::Unavailable => Status::Unavailable,
+            Outcome::AuthRequired => Status::AuthRequired,
+            Outcome::Partial => Status::Partial,
+            Outcome::Complete => Status::Complete,
+            Outcome::Archived => Status::Archived,
+        }
+    }
 }
 
 
+fn main() {
+    report::start();
 
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
     // let Cli { url, cookie } = parser::parse_cli(&matches);
     let cli: Cli  = parser::parse_cli(&matches);
-    let url = cli.url;
-    let cookie = cli.cookie;
+    if let Err(e) = logger::init(cli.log_level, cli.log_format, cli.log_file.as_deref()) {
+        eprintln!("Error: open log file failed: {}", e);
+        std::process::exit(ExitCode::Error as i32);
+    }
+    // --report - 时stdout只输出报告
+    if cli.report.as_deref() == Some(Path::new("-")) {
+        progress::use_stderr();
+    }
+    interrupt::install();
+
+    let error = run(&cli, &matches).err().map(|e| {
+        match e.downcast_ref::<DownloadError>() {
+            // 🔴 被封：进度已经写入图集库，重新运行同样的命令即可继续
+            Some(DownloadError::Banned(remaining)) => {
+                logger::error(&format!("Your IP has been temporarily banned, the ban expires in {}.", ban::format_duration(*remaining)));
+                logger::error("Progress has been saved, run the same command again to continue.");
+                logger::error("Consider:");
+                logger::error("  • Wait for a while and try again");
+                logger::error("  • Switch IP and try again");
+                logger::error("  • Use --wait-on-ban to wait until the ban expires and continue automatically");
+            }
+            Some(DownloadError::Interrupted) => {
+                logger::warn("Interrupted, progress has been saved, run the same command again to continue.");
+            }
+            Some(DownloadError::QuotaExceeded) => {
+                logger::error("Error: Image quota exceeded, progress has been saved.");
+                logger::error("Consider:");
+                logger::error("  • Run the same command again after the quota resets");
+                logger::error("  • Use --on-quota wait|archive|torrent to continue automatically");
+            }
+            _ => logger::error(&format!("Error: {}", e)),
+        }
+        match e.downcast_ref::<DownloadError>() {
+            Some(DownloadError::Banned(_)) => ExitCode::Banned,
+            Some(DownloadError::Interrupted) => ExitCode::Interrupted,
+            Some(DownloadError::QuotaExceeded) => ExitCode::QuotaExceeded,
+            _ => ExitCode::Error,
+        }
+    });
+
+    let code = report::exit_code(error);
+    if let Some(path) = &cli.report {
+        if let Err(e) = report::write(path, code) {
+            logger::error(&format!("Error: write report {} failed: {}", path.display(), e));
+        }
+    }
+    if code != ExitCode::Success {
+        std::process::exit(code as i32);
+    }
+}
+
+
+fn run(cli: &Cli, matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let mut library = Library::open(&cli.library)?;
+
+    match matches.subcommand() {
+        ("watch", Some(sub)) => return run_watch(cli, sub, &mut library),
+        ("update", Some(sub)) => return run_update(cli, sub, &mut library),
+        ("verify", Some(sub)) => return run_verify(sub, &library),
+        ("favorites", Some(sub)) => return run_favorites(cli, sub, &mut library),
+        ("list", _) => {
+            print_entries(library.entries());
+            return Ok(());
+        }
+        ("query", Some(sub)) => {
+            let terms: Vec<String> = sub.values_of("terms").unwrap().map(String::from).collect();
+            print_entries(library.query(&terms));
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // 没有子命令时clap会保证提供了url
+    let url = cli.url.clone().expect("Should provide the url.");
+    let source = cli.source.clone().expect("Should provide the url.");
+    let cookie = cli.cookie.clone();
 
     let host = url.host().unwrap().to_string();
 
     let h = Handler::new(&host, &cookie);
-    let m = Manga::new(&h, &url);
 
-    println!("Collect Download information");
-    let download_urls = m.get_download_urls(&h);
+    // 列表类url(tag/uploader/favorites/search)：先展开成图集列表，再逐个走正常的下载流程
+    if source.is_listing() {
+        logger::info(&format!("Collect galleries from {:?} listing", source));
+        let galleries = source::expand_listing(&h, &url, cli.limit);
+        if galleries.is_empty() {
+            logger::error(&format!("Error: No galleries found in {}", url));
+            let mut status = Status::Unavailable;
+            if host == "exhentai.org" && cookie.trim().is_empty() {
+                logger::error("  • Accessing exhentai.org requires a valid login cookie.");
+                logger::error("    Please provide one using: -c <cookie_file>");
+                status = Status::AuthRequired;
+            }
+            report::add(GalleryReport::new(url.as_str(), status));
+            return Ok(());
+        }
+
+        logger::info(&format!("Found {} galleries", galleries.len()));
+        for (i, gallery) in galleries.iter().enumerate() {
+            logger::info(&format!("[{}/{}] {}", i + 1, galleries.len(), gallery));
+            match download_gallery(&h, gallery, cli, &mut library)? {
+                Outcome::Unavailable | Outcome::AuthRequired => logger::warn(&format!("Skip gallery: {}", gallery)),
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+
+    download_gallery(&h, &url, cli, &mut library)?;
+
+    Ok(())
+}
+
+
+// 以前 watch 子命令默认读取的配置文件
+const LEGACY_WATCH_CONFIG: &str = "watch.toml";
+
+// watch 子命令：检查保存的搜索，把没下载过的图集交给正常的下载流程
+// 只有完整下载的图集才算下载过，部分失败的下次还会重新尝试
+fn run_watch(cli: &Cli, matches: &clap::ArgMatches, library: &mut Library) -> Result<(), Box<dyn std::error::Error>> {
+    // 兼容以前的默认文件 watch.toml(现在保存的搜索写在 --config 中)
+    let mut config_path = cli.config.as_path();
+    if !config_path.exists() && Path::new(LEGACY_WATCH_CONFIG).exists() {
+        logger::warn(&format!(
+            "Using {}, please move the saved searches into {} or pass --config {}",
+            LEGACY_WATCH_CONFIG,
+            config_path.display(),
+            LEGACY_WATCH_CONFIG
+        ));
+        config_path = Path::new(LEGACY_WATCH_CONFIG);
+    }
+    if !config_path.exists() {
+        return Err(format!(
+            "Watch config {} not found, put the saved searches ([[search]]) in it or use --config <file>",
+            config_path.display()
+        )
+        .into());
+    }
+    let config = WatchConfig::load(config_path)
+        .map_err(|e| format!("Load watch config {} failed: {}", config_path.display(), e))?;
+    if config.searches.is_empty() {
+        return Err(format!(
+            "No saved searches ([[search]]) in {}, use --config <file> to choose the config file",
+            config_path.display()
+        )
+        .into());
+    }
+
+    let interval = match matches.value_of("interval") {
+        Some(n) => n
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("Incorrect interval '{}', should be a number of seconds", n))?,
+        None => config.interval,
+    };
+    let daemon = matches.is_present("daemon");
+    // 间隔为0时守护模式会不停地请求列表页
+    if daemon && interval == 0 {
+        return Err("Interval must be greater than 0 in daemon mode".into());
+    }
+
+    loop {
+        let queue = watch::collect_new(&config, &cli.cookie, library);
+        logger::info(&format!("[watch] {} new galleries queued", queue.len()));
+
+        for (i, gallery) in queue.iter().enumerate() {
+            logger::info(&format!("[{}/{}] {}", i + 1, queue.len(), gallery));
+            let host = gallery.host_str().unwrap_or_default().to_string();
+            let h = Handler::new(&host, &cli.cookie);
+
+            match download_gallery(&h, gallery, cli, library)? {
+                Outcome::Complete | Outcome::Archived => {}
+                Outcome::Partial => logger::warn(&format!("Incomplete gallery, will retry next run: {}", gallery)),
+                Outcome::Unavailable | Outcome::AuthRequired => logger::warn(&format!("Skip gallery: {}", gallery)),
+            }
+        }
+
+        if !daemon {
+            break;
+        }
+
+        logger::info(&format!("[watch] Next check in {}s", interval));
+        interrupt::sleep(Duration::from_secs(interval));
+        interrupt::check()?;
+    }
+
+    Ok(())
+}
+
+
+fn run_favorites(cli: &Cli, matches: &clap::ArgMatches, library: &mut Library) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.cookie.trim().is_empty() {
+        return Err("Favorites require a login cookie, please provide one using: -c <cookie_file>".into());
+    }
+    let base = Url::parse(matches.value_of("base-url").unwrap_or(favorites::DEFAULT_BASE_URL))
+        .map_err(|e| format!("Incorrect base url: {}", e))?;
+    let fav = Favorites::new(&base, &cli.cookie);
+
+    match matches.subcommand() {
+        ("list", _) => {
+            for c in fav.categories()? {
+                progress::println(&format!("{}  {:>6}  {}", c.index, c.count, c.name));
+            }
+        }
+        ("add", Some(sub)) => {
+            let gallery = parse_gallery_url(sub.value_of("gallery").unwrap())?;
+            let category = parse_category(sub.value_of("category").unwrap_or("0"))?;
+            fav.add(&gallery, category, sub.value_of("note").unwrap_or(""))?;
+            logger::info(&format!("Added {} to favorites {}", gallery, category));
+        }
+        ("remove", Some(sub)) => {
+            let gallery = parse_gallery_url(sub.value_of("gallery").unwrap())?;
+            fav.remove(&gallery)?;
+            logger::info(&format!("Removed {} from favorites", gallery));
+        }
+        ("sync", Some(sub)) => {
+            let categories: Vec<Option<u8>> = match sub.values_of("category") {
+                Some(values) => values.map(|v| parse_category(v).map(Some)).collect::<Result<_, _>>()?,
+                None => vec![None],
+            };
+
+            let mut queue: Vec<Url> = vec![];
+            for category in categories {
+                let url = fav.category_url(category);
+                for gallery in source::expand_listing(fav.handler(), &url, cli.limit) {
+                    let downloaded = source::gallery_id(&gallery).and_then(|id| library.get(id)).is_some_and(|e| e.is_available());
+                    if !downloaded && !queue.contains(&gallery) {
+                        queue.push(gallery);
+                    }
+                }
+            }
+            logger::info(&format!("[favorites] {} galleries to download", queue.len()));
+
+            for (i, gallery) in queue.iter().enumerate() {
+                logger::info(&format!("[{}/{}] {}", i + 1, queue.len(), gallery));
+                match download_gallery(fav.handler(), gallery, cli, library)? {
+                    Outcome::Complete | Outcome::Archived => {}
+                    Outcome::Partial => logger::warn(&format!("Incomplete gallery, will retry next sync: {}", gallery)),
+                    Outcome::Unavailable | Outcome::AuthRequired => logger::warn(&format!("Skip gallery: {}", gallery)),
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+
+fn parse_gallery_url(value: &str) -> Result<Url, String> {
+    match Url::parse(value) {
+        Ok(url) if source::gallery_id(&url).is_some() => Ok(url),
+        _ => Err(format!("'{}' is not a gallery url (/g/<id>/<token>/)", value)),
+    }
+}
+
+
+fn parse_category(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(n) if n <= 9 => Ok(n),
+        _ => Err(format!("'{}' is not a favorite category (0-9)", value)),
+    }
+}
+
+
+fn print_entries<'a>(entries: impl Iterator<Item = &'a Entry>) {
+    for e in entries {
+        progress::println(&format!(
+            "{:>8}  {}  {:>4}p  {}  {}",
+            e.id,
+            library::format_date(e.downloaded_at),
+            e.pages,
+            match (e.complete, e.archive_file().is_some()) {
+                (true, _) => "complete  ",
+                (false, true) => "archived  ",
+                (false, false) => "incomplete",
+            },
+            e.title
+        ));
+    }
+}
+
+
+// 下载单个图集，完成后把结果写入本地图集库
+fn download_gallery(h: &Handler, url: &Url, cli: &Cli, library: &mut Library) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let cookie = cli.cookie.as_str();
+
+    // 已经完整下载过并且目录还在的图集直接跳过，不再重新抓取页面
+    if let Some(entry) = source::gallery_id(url).and_then(|id| library.get(id)) {
+        if entry.is_available() && !cli.force {
+            logger::info(&format!("Already in library: {} ({})", entry.title, entry.path));
+            let mut r = GalleryReport::new(url.as_str(), Status::Complete);
+            r.id = Some(entry.id);
+            r.title = Some(entry.title.clone());
+            r.total_pages = entry.pages;
+            r.skipped = entry.pages;
+            report::add(r);
+            return Ok(Outcome::Complete);
+        }
+    }
+
+    let started = Instant::now();
+    let m = match Manga::new(h, url) {
+        Ok(m) => m,
+        Err(e) if matches!(e.status(), Some(StatusCode::NOT_FOUND) | Some(StatusCode::GONE)) => {
+            logger::error(&format!("Error: Gallery not found: {} ({})", url, e));
+            report::add(GalleryReport::new(url.as_str(), Status::Unavailable));
+            return Ok(Outcome::Unavailable);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if cli.torrent {
+        return download_torrent(h, &m, cli, started);
+    }
+
+    logger::info("Collect Download information");
+    let mpv = open_mpv(h, &m, cli)?;
+    // --thumbnails 需要缩略图页，没有使用MPV时页面链接也从中得到
+    let thumbnails = match cli.thumbnails {
+        true => m.get_page_thumbnails(h, cli.pages.as_ref())?,
+        false => vec![],
+    };
+    let links = match &mpv {
+        Some(mpv) => mpv.page_links(cli.pages.as_ref()),
+        None if cli.thumbnails => thumbnails.iter().map(|t| t.link.clone()).collect(),
+        None => m.get_page_links(h, cli.pages.as_ref())?,
+    };
 
     //未获取到页面数据-可能是缺少cookie或者这是一个存在着”内容警告“的图集，就会下载失败
     //例如: https://e-hentai.org/g/3809093/c06ff2b95a/
-    if download_urls.is_empty() {
-        eprintln!("Error: No downloadable resources found.");
-        eprintln!("Possible reasons:");
+    if links.is_empty() {
+        logger::error("Error: No downloadable resources found.");
+        logger::error("Possible reasons:");
         // 1️⃣ 图集不存在
-        eprintln!("  • The gallery may not exist.");
+        logger::error("  • The gallery may not exist.");
         // 2️⃣ 被标记为受限制
-        eprintln!("  • The gallery may be flagged as restricted or containing offensive content and cannot be downloaded.");
+        logger::error("  • The gallery may be flagged as restricted or containing offensive content and cannot be downloaded.");
         // 3️⃣ 特殊域名提示
-        if host == "exhentai.org" {
-            if cookie.trim().is_empty() {
-                eprintln!("  • Accessing exhentai.org requires a valid login cookie.");
-                eprintln!("    Please provide one using: -c <cookie_file>");
+        let mut outcome = Outcome::Unavailable;
+        if h.host == "exhentai.org" && cookie.trim().is_empty() {
+            logger::error("  • Accessing exhentai.org requires a valid login cookie.");
+            logger::error("    Please provide one using: -c <cookie_file>");
+            outcome = Outcome::AuthRequired;
+        }
+        report::add(gallery_report(&m, &outcome, 0, 0, Stats::default(), started));
+        return Ok(outcome);
+    }
+
+
+    let path = format!("tmp{}", m.number);
+    if !Path::new(&path).exists() {
+        fs::create_dir(&path)?;
+    }
+
+    save_metadata(&m, &path);
+    save_previews(&m, &thumbnails, &path, cli);
+
+    // 其他图集中已经下载过的相同图片直接复用，不消耗图片配额
+    let fetch = if cli.force {
+        links.clone()
+    } else {
+        reuse_pages(&links, &path, |hash| library.find_hash(hash))?
+    };
+    let reused = links.len() - fetch.len();
+    // 配额已经用完并且会改用压缩包/种子时，不再尝试下载图片
+    let skip_images = report::quota_exceeded() && matches!(cli.on_quota, QuotaStrategy::Archive | QuotaStrategy::Torrent);
+    let (mut outcome, mut stats, mut originals) = match skip_images {
+        true => (Outcome::Partial, Stats::default(), BTreeSet::new()),
+        false => fetch_pages(h, fetch, &path, cli, mpv.clone()),
+    };
+
+    // --on-quota wait：配额恢复后继续下载目录中还没有的页面
+    while outcome != Outcome::Complete && report::quota_exceeded() && cli.on_quota == QuotaStrategy::Wait {
+        record(library, &m, &path, &links, &originals, false)?;
+        quota::wait_for_reset(&cli.cookie, cli.quota_poll)?;
+        report::clear_quota_exceeded();
+        let remaining: Vec<String> = links
+            .iter()
+            .filter(|link| library::find_local(Path::new(&path), manga::page_name(link)).is_none())
+            .cloned()
+            .collect();
+        let (next, next_stats, next_originals) = fetch_pages(h, remaining, &path, cli, mpv.clone());
+        outcome = next;
+        stats.merge(next_stats);
+        originals.extend(next_originals);
+    }
+
+    // 压缩包包含所有页面，图集记录为已归档(目录中的页面仍然不完整)；种子只是下载方式，图集仍然是不完整的
+    if outcome != Outcome::Complete && report::quota_exceeded() && quota_fallback(h, &m, &path, cli) && cli.on_quota == QuotaStrategy::Archive {
+        outcome = Outcome::Archived;
+    }
+
+    record(library, &m, &path, &links, &originals, outcome == Outcome::Complete && cli.pages.is_none())?;
+    report::add(gallery_report(&m, &outcome, links.len(), reused, stats, started));
+
+    // --on-quota stop：进度已经保存，不再下载后面的图集
+    if report::quota_exceeded() && cli.on_quota == QuotaStrategy::Stop {
+        return Err(DownloadError::QuotaExceeded.into());
+    }
+
+    // 下载过程中被封或者按下Ctrl-C时，进度已经保存，直接中止后续的图集
+    Handler::check_ban()?;
+    interrupt::check()?;
+
+
+    Ok(outcome)
+}
+
+
+// --torrent：只下载种子文件，没有种子的图集报告为不可用
+fn download_torrent(h: &Handler, m: &Manga, cli: &Cli, started: Instant) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let path = format!("tmp{}", m.number);
+    let outcome = match save_torrent(h, m, &path, cli)? {
+        true => {
+            save_metadata(m, &path);
+            Outcome::Complete
+        }
+        false => {
+            logger::error(&format!("Error: No torrents available for gallery {}", m.number));
+            Outcome::Unavailable
+        }
+    };
+    report::add(gallery_report(m, &outcome, 0, 0, Stats::default(), started));
+    Ok(outcome)
+}
+
+
+// 列出图集的种子并下载做种最多的一个，没有种子时返回false
+fn save_torrent(h: &Handler, m: &Manga, path: &str, cli: &Cli) -> Result<bool, DownloadError> {
+    let torrents = torrent::list(h, m)?;
+    let best = match torrent::best(&torrents) {
+        Some(t) => t,
+        None => return Ok(false),
+    };
+
+    logger::info(&format!("{} torrents for {}:", torrents.len(), m.title));
+    for t in &torrents {
+        logger::info(&format!(
+            "  {} {:>12}  seeds {:<4} peers {:<4} downloads {:<5} {}",
+            t.posted, t.size, t.seeds, t.peers, t.downloads, t.name
+        ));
+    }
+
+    fs::create_dir_all(path).map_err(DownloadError::Io)?;
+    let fname = torrent::download(best, m, path, &cli.cookie)?;
+    logger::info(&format!("Saved torrent {} ({})", fname.display(), best.name));
+
+    if let Some(command) = &cli.torrent_client {
+        match torrent::open_with(command, &fname) {
+            Ok(status) if status.success() => {}
+            Ok(status) => logger::warn(&format!("Torrent client exited with {}", status)),
+            Err(e) => logger::warn(&format!("Run torrent client failed: {}", e)),
+        }
+    }
+    Ok(true)
+}
+
+
+// --on-quota archive/torrent：配额用完后改为下载压缩包或者种子，返回是否成功
+fn quota_fallback(h: &Handler, m: &Manga, path: &str, cli: &Cli) -> bool {
+    let result = match cli.on_quota {
+        QuotaStrategy::Archive => {
+            logger::info("Image quota exceeded, download the archive instead");
+            archive::download(m, path, &cli.cookie).map(|f| {
+                logger::info(&format!("Saved archive {}", f.display()));
+                true
+            })
+        }
+        QuotaStrategy::Torrent => {
+            logger::info("Image quota exceeded, download the torrent instead");
+            save_torrent(h, m, path, cli)
+        }
+        QuotaStrategy::Stop | QuotaStrategy::Wait => return false,
+    };
+    match result {
+        Ok(true) => true,
+        Ok(false) => {
+            logger::warn(&format!("No torrents available for gallery {}", m.number));
+            false
+        }
+        Err(e) => {
+            logger::warn(&format!("Quota fallback failed for gallery {}: {}", m.number, e));
+            false
+        }
+    }
+}
+
+
+fn is_image_file(file: &Path) -> bool {
+    !matches!(file.file_name().and_then(|n| n.to_str()), Some(metadata::FILENAME | preview::COVER))
+        && !matches!(file.extension().and_then(|e| e.to_str()), Some("torrent" | "zip"))
+}
+
+
+fn save_metadata(m: &Manga, path: &str) {
+    if let Err(e) = metadata::write(m, path) {
+        logger::warn(&format!("Save {} failed: {}", metadata::FILENAME, e));
+    }
+}
+
+
+// 封面和缩略图失败不影响图集的下载，只给出警告
+fn save_previews(m: &Manga, thumbnails: &[Thumbnail], path: &str, cli: &Cli) {
+    if cli.cover {
+        if let Err(e) = preview::save_cover(m, path, &cli.cookie, cli.force) {
+            logger::warn(&format!("Save cover failed: {}", e));
+        }
+    }
+    if cli.thumbnails {
+        match preview::save_thumbnails(thumbnails, path, &cli.cookie, cli.force) {
+            Ok(0) => {}
+            Ok(n) => logger::info(&format!("Saved {} thumbnails", n)),
+            Err(e) => logger::warn(&format!("Save thumbnails failed: {}", e)),
+        }
+    }
+}
+
+
+// --resolver：auto 时先尝试MPV，账号没有开启MPV或者打开失败时使用缩略图页
+fn open_mpv(h: &Handler, m: &Manga, cli: &Cli) -> Result<Option<Arc<Mpv>>, Box<dyn std::error::Error>> {
+    if cli.resolver == Resolver::Pages {
+        return Ok(None);
+    }
+    let opened = match Mpv::open(h, m, &cli.cookie) {
+        Ok(opened) => opened,
+        // 被封和Ctrl-C换一种方式也没有用
+        Err(e @ (DownloadError::Banned(_) | DownloadError::Interrupted)) => return Err(e.into()),
+        Err(e) if cli.resolver == Resolver::Auto => {
+            logger::warn(&format!("Open the multi-page viewer failed ({}), use the thumbnail pages", e));
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match opened {
+        Some(mpv) => {
+            logger::info("Resolve images with the multi-page viewer");
+            Ok(Some(Arc::new(mpv)))
+        }
+        None if cli.resolver == Resolver::Mpv => {
+            Err("The multi-page viewer is not available, enable it in the account settings or use --resolver pages".into())
+        }
+        None => {
+            logger::debug("The multi-page viewer is not available, use the thumbnail pages");
+            Ok(None)
+        }
+    }
+}
+
+
+// complete 为 false 时下次运行会继续下载；只下载了部分页面(--pages)的图集也不算完整
+// originals 为这次下载的、服务器提供原图的页面
+fn record(
+    library: &mut Library,
+    m: &Manga,
+    path: &str,
+    links: &[String],
+    originals: &BTreeSet<String>,
+    complete: bool,
+) -> std::io::Result<()> {
+    // 之前下载其他页面时记录的hash保留下来
+    let mut hashes = library.get(m.number).map(|e| e.hashes.clone()).unwrap_or_default();
+    let mut verified = library.get(m.number).map(|e| e.originals.clone()).unwrap_or_default();
+    verified.extend(originals.iter().cloned());
+    hashes.extend(
+        links
+            .iter()
+            .filter_map(|link| manga::page_hash(link).map(|hash| (manga::page_name(link).to_string(), hash.to_string()))),
+    );
+
+    library.upsert(Entry {
+        id: m.number,
+        token: m.token.clone(),
+        title: m.title.clone(),
+        tags: m.tags.clone(),
+        pages: m.length.map_or(links.len(), |n| n as usize),
+        url: m.url.to_string(),
+        path: path.to_string(),
+        hashes,
+        originals: verified,
+        downloaded_at: 0,
+        complete,
+        archive: Some(archive::path(path, m.number))
+            .filter(|p| p.is_file())
+            .map(|p| p.display().to_string()),
+    })
+}
+
+
+// 按照图片hash(/s/<hash>/)在本地查找相同的图片，找到的硬链接/复制到path中，返回仍然需要下载的页面链接
+// 截断或损坏的文件不会被复用，这些页面重新下载
+fn reuse_pages<F>(links: &[String], path: &str, lookup: F) -> std::io::Result<Vec<String>>
+where
+    F: Fn(&str) -> Vec<PathBuf>,
+{
+    let mut fetch: Vec<String> = vec![];
+    let mut reused = 0;
+    for link in links {
+        let name = manga::page_name(link);
+        // 目标目录中已经存在的页面交给下载流程处理
+        if library::find_local(Path::new(path), name).is_some() {
+            fetch.push(link.clone());
+            continue;
+        }
+        let source = manga::page_hash(link)
+            .map(&lookup)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|file| is_reusable(file));
+        match source {
+            Some(file) => {
+                let ext = file.extension().and_then(|e| e.to_str()).unwrap_or("jpg");
+                let dest = Path::new(path).join(format!("{}.{}", name, ext));
+                library::link_or_copy(&file, &dest)?;
+                logger::debug(&format!("Reuse {} -> {}", file.display(), dest.display()));
+                reused += 1;
+            }
+            None => fetch.push(link.clone()),
+        }
+    }
+    if reused > 0 {
+        logger::info(&format!("Reuse {} pages already downloaded, fetch {} pages", reused, fetch.len()));
+    }
+    Ok(fetch)
+}
+
+
+// 复用前检查来源文件：基本检查之外总是检查完整的图片结构，不依赖 --deep-verify
+fn is_reusable(file: &Path) -> bool {
+    let valid = Handler::verify_file(file, None).is_ok()
+        && fs::read(file).is_ok_and(|data| validate::check_structure(&data).is_ok());
+    if !valid {
+        logger::warn(&format!("Not reusing invalid file {}", file.display()));
+    }
+    valid
+}
+
+
+// 解析需要下载的页面的图片地址并下载
+// 同时返回服务器提供的是原图(SHA-1已经校验过)的页面
+fn fetch_pages(
+    h: &Handler,
+    fetch: Vec<String>,
+    path: &str,
+    cli: &Cli,
+    mpv: Option<Arc<Mpv>>,
+) -> (Outcome, Stats, BTreeSet<String>) {
+    if fetch.is_empty() {
+        logger::info("All downloads completed successfully.");
+        return (Outcome::Complete, Stats::default(), BTreeSet::new());
+    }
+    let total = fetch.len();
+    progress::start(total);
+    let pages = Manga::resolve_links(h, fetch, cli.concurrency, mpv);
+    let unresolved = total - pages.len();
+    let originals: BTreeSet<String> = pages
+        .iter()
+        .filter(|page| page.hash.is_some())
+        .filter_map(|page| Path::new(&page.filename).file_stem().and_then(|s| s.to_str()).map(String::from))
+        .collect();
+
+    let outcome = download_pages(pages, path, cli);
+    let stats = progress::finish();
+    if unresolved > 0 {
+        logger::info(&format!("{} pages could not be resolved.", unresolved));
+        return (Outcome::Partial, stats, originals);
+    }
+    (outcome, stats, originals)
+}
+
+
+// 单个图集的运行报告，reused 为从其他图集复用的页面数
+fn gallery_report(m: &Manga, outcome: &Outcome, total: usize, reused: usize, stats: Stats, started: Instant) -> GalleryReport {
+    let mut r = GalleryReport::new(m.url.as_str(), outcome.status());
+    r.id = Some(m.number);
+    r.title = Some(m.title.clone());
+    r.total_pages = total;
+    r.skipped = reused;
+    r.add_stats(stats);
+    r.duration_ms = started.elapsed().as_millis() as u64;
+    r
+}
+
+
+// update 子命令：检查图集库中的图集是否有新版本(Newer version)，有的话下载最新的版本
+fn run_update(cli: &Cli, matches: &clap::ArgMatches, library: &mut Library) -> Result<(), Box<dyn std::error::Error>> {
+    let ids: Vec<u32> = matches
+        .values_of("ids")
+        .into_iter()
+        .flatten()
+        .map(|id| id.trim().parse::<u32>().map_err(|_| format!("Incorrect gallery id '{}'", id)))
+        .collect::<Result<_, _>>()?;
+
+    let entries: Vec<Entry> = library
+        .entries()
+        .filter(|e| ids.is_empty() || ids.contains(&e.id))
+        .cloned()
+        .collect();
+
+    for entry in entries {
+        let url = entry.gallery_url();
+        let h = Handler::new(url.host_str().unwrap_or_default(), &cli.cookie);
+        // 已经被删除的图集很常见，跳过它继续检查后面的图集
+        let m = match Manga::new(&h, &url) {
+            Ok(m) => m,
+            Err(e) => {
+                skip_unavailable(&url, e)?;
+                continue;
+            }
+        };
+
+        if let Some(parent) = &m.parent {
+            logger::info(&format!("{} parent: {}", entry.id, parent));
+        }
+        let newest = match m.newer.last() {
+            Some(u) => u.clone(),
+            None => {
+                logger::info(&format!("{} is up to date: {}", entry.id, entry.title));
+                continue;
+            }
+        };
+        logger::info(&format!("{} has a newer version: {}", entry.id, newest));
+
+        if let Some(e) = source::gallery_id(&newest).and_then(|id| library.get(id)) {
+            if e.is_available() && !cli.force {
+                logger::info(&format!("Already in library: {} ({})", e.title, e.path));
+                continue;
+            }
+        }
+
+        if update_gallery(&h, &m, &entry, &newest, cli, library)? == Outcome::Unavailable {
+            logger::warn(&format!("Skip gallery: {}", newest));
+        }
+    }
+
+    Ok(())
+}
+
+
+// update 中无法打开的图集：记录为不可用并继续下一个图集，被封和Ctrl-C时仍然中止
+fn skip_unavailable(url: &Url, e: DownloadError) -> Result<(), DownloadError> {
+    match e {
+        DownloadError::Banned(_) | DownloadError::Interrupted => return Err(e),
+        _ if matches!(e.status(), Some(StatusCode::NOT_FOUND) | Some(StatusCode::GONE)) => {
+            logger::error(&format!("Error: Gallery not found: {} ({})", url, e));
+        }
+        _ => logger::error(&format!("Error: Load gallery {} failed: {}", url, e)),
+    }
+    report::add(GalleryReport::new(url.as_str(), Status::Unavailable));
+    Ok(())
+}
+
+
+// verify 子命令：重新检查已下载图集目录中的文件，列出损坏或缺失的页面
+// 图集库中有记录时按照保存的hash校验SHA-1，否则只检查文件大小和图片格式
+fn run_verify(matches: &clap::ArgMatches, library: &Library) -> Result<(), Box<dyn std::error::Error>> {
+    let target = matches.value_of("gallery").unwrap();
+    let entry = target
+        .parse::<u32>()
+        .ok()
+        .and_then(|id| library.get(id))
+        .or_else(|| library.entries().find(|e| Path::new(&e.path) == Path::new(target)));
+    let dir = entry.map(|e| PathBuf::from(&e.path)).unwrap_or_else(|| PathBuf::from(target));
+
+    if !dir.is_dir() {
+        return Err(format!("{} is neither a gallery id in the library nor a directory", target).into());
+    }
+
+    let mut missing: Vec<String> = vec![];
+    let mut corrupt: Vec<(PathBuf, String)> = vec![];
+    let mut checked: Vec<PathBuf> = vec![];
+    let mut ok = 0;
+
+    if let Some(entry) = entry {
+        for (stem, hash) in &entry.hashes {
+            match library::find_local(&dir, stem) {
+                Some(file) => {
+                    // 只有下载时服务器提供的是原图，hash才对得上；缩小过的图片只检查结构
+                    let result = match entry.originals.contains(stem) {
+                        true => Handler::verify_file(&file, Some(hash)).map(|_| ()).map_err(|e| e.to_string()),
+                        false => check_image(&file),
+                    };
+                    match result {
+                        Ok(_) => ok += 1,
+                        Err(e) => corrupt.push((file.clone(), e.to_string())),
+                    }
+                    checked.push(file);
+                }
+                None => missing.push(stem.clone()),
+            }
+        }
+    }
+
+    // 已归档的图集：目录中没有的页面在压缩包中，不算缺失
+    let archive = entry.and_then(|e| e.archive_file());
+    if let Some(archive) = archive {
+        if !missing.is_empty() {
+            progress::println(&format!("{} pages are only in the archive {}", missing.len(), archive.display()));
+            missing.clear();
+        }
+    }
+
+    // 图集库中没有记录的文件只做基本检查，info.json、封面、种子和压缩包不是页面
+    for file in fs::read_dir(&dir)?.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if !file.is_file() || checked.contains(&file) || !is_image_file(&file) {
+            continue;
+        }
+        match check_image(&file) {
+            Ok(_) => ok += 1,
+            Err(e) => corrupt.push((file, e)),
+        }
+    }
+
+    missing.sort_by_key(|stem| page_number(stem));
+    corrupt.sort_by_key(|(file, _)| page_number(file.file_stem().and_then(|s| s.to_str()).unwrap_or("")));
+
+    // 和日志一样，--report - 时输出到stderr
+    for stem in &missing {
+        progress::println(&format!("Missing: {}", stem));
+    }
+    for (file, reason) in &corrupt {
+        progress::println(&format!("Corrupt: {} ({})", file.display(), reason));
+    }
+    progress::println(&format!(
+        "{}: {} ok, {} corrupt, {} missing",
+        dir.display(),
+        ok,
+        corrupt.len(),
+        missing.len()
+    ));
+
+    // 结果也写入运行报告，退出码和 --report 与下载时一致
+    let status = match (missing.is_empty() && corrupt.is_empty(), archive) {
+        (true, Some(_)) => Status::Archived,
+        (true, None) => Status::Complete,
+        (false, _) => Status::Partial,
+    };
+    let url = entry.map(|e| e.url.clone()).unwrap_or_else(|| dir.display().to_string());
+    let mut gallery = GalleryReport::new(&url, status);
+    gallery.id = entry.map(|e| e.id);
+    gallery.title = entry.map(|e| e.title.clone());
+    gallery.total_pages = ok + corrupt.len() + missing.len();
+    gallery.failed = missing
+        .into_iter()
+        .map(|stem| Failure {
+            file: stem,
+            class: ErrorClass::Verification,
+            retryable: true,
+            error: "missing".to_string(),
+        })
+        .chain(corrupt.into_iter().map(|(file, reason)| Failure {
+            file: file.display().to_string(),
+            class: ErrorClass::Verification,
+            retryable: true,
+            error: reason,
+        }))
+        .collect();
+    report::add(gallery);
+    Ok(())
+}
+
+
+// verify 中不比较hash的检查：大小、类型和图片结构
+fn check_image(file: &Path) -> Result<(), String> {
+    Handler::verify_file(file, None).map_err(|e| e.to_string())?;
+    validate::check_structure(&fs::read(file).map_err(|e| e.to_string())?)
+}
+
+
+// 文件名 <id>-<n> 中的页码，用于按页面顺序输出
+fn page_number(stem: &str) -> u32 {
+    manga::page_index(stem).unwrap_or(0)
+}
+
+
+// 下载新版本的图集：hash(/s/<hash>/)与旧版本相同的页面直接复用本地文件，只下载变化了的页面
+fn update_gallery(h: &Handler, old: &Manga, entry: &Entry, newest: &Url, cli: &Cli, library: &mut Library) -> Result<Outcome, Box<dyn std::error::Error>> {
+    // 旧的记录中没有保存hash时，重新抓取旧版本的页面链接来建立对应关系
+    let mut local: HashMap<String, PathBuf> = HashMap::new();
+    if entry.hashes.is_empty() {
+        let old_links = match old.get_page_links(h, None) {
+            Ok(links) => links,
+            Err(e) => {
+                skip_unavailable(&old.url, e)?;
+                return Ok(Outcome::Unavailable);
+            }
+        };
+        for link in old_links {
+            let file = manga::page_hash(&link).and_then(|hash| {
+                library::find_local(Path::new(&entry.path), manga::page_name(&link)).map(|f| (hash.to_string(), f))
+            });
+            if let Some((hash, file)) = file {
+                local.insert(hash, file);
             }
         }
-        std::process::exit(1);
     }
 
+    let started = Instant::now();
+    let loaded = Manga::new(h, newest).and_then(|m| m.get_page_links(h, None).map(|links| (m, links)));
+    let (m, links) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            skip_unavailable(newest, e)?;
+            return Ok(Outcome::Unavailable);
+        }
+    };
+    if links.is_empty() {
+        logger::error(&format!("Error: No downloadable resources found in {}", newest));
+        report::add(gallery_report(&m, &Outcome::Unavailable, 0, 0, Stats::default(), started));
+        return Ok(Outcome::Unavailable);
+    }
 
     let path = format!("tmp{}", m.number);
     if !Path::new(&path).exists() {
         fs::create_dir(&path)?;
     }
 
+    let fetch = if cli.force {
+        links.clone()
+    } else {
+        reuse_pages(&links, &path, |hash| {
+            local.get(hash).cloned().into_iter().chain(library.find_hash(hash)).collect()
+        })?
+    };
+    let reused = links.len() - fetch.len();
+    let (outcome, stats, originals) = fetch_pages(h, fetch, &path, cli, None);
+
+    record(library, &m, &path, &links, &originals, outcome == Outcome::Complete)?;
+    report::add(gallery_report(&m, &outcome, links.len(), reused, stats, started));
+    Handler::check_ban()?;
+    interrupt::check()?;
+    Ok(outcome)
+}
 
-    //-r | --retry 参数获取
-    let force_retry = matches.is_present("retry");
-    // let force_retry = cli.retry;
+
+// 使用线程池下载所有页面，失败的页面按照重试策略重试
+fn download_pages(download_urls: Vec<Page>, path: &str, cli: &Cli) -> Outcome {
+    let cookie = cli.cookie.as_str();
+    let force_retry = cli.retry;
 
     //
     let mut pending_tasks = download_urls;
 
 
-    let pool = ThreadPool::new(16);
+    let pool = ThreadPool::new(cli.concurrency);
+    // 已经放弃的页面(文件名)，不再加入重试列表
+    let gave_up: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
 
     //新增循环逻辑
     loop {
@@ -109,56 +1045,97 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let failed_tasks = Arc::new(Mutex::new(Vec::new()));
 
         // for (target, filename) in pending_tasks.clone() {
-        for (target, filename) in pending_tasks.iter().cloned(){
-            let path = path.clone();
-            let cookie = cookie.clone();
+        for page in pending_tasks.drain(..) {
+            let path = path.to_string();
+            let cookie = cookie.to_string();
             let failed_tasks = Arc::clone(&failed_tasks);
+            let gave_up = Arc::clone(&gave_up);
 
-            pool.execute(move || {
-                let max_retries = 5;
+            let policy = cli.retry_policy.clone();
 
+            pool.execute(move || {
                 let mut success = false;
+                // 不可重试的错误，-r 也不再重试
+                let mut permanent = false;
+                let mut last_mismatch: Option<String> = None;
 
-                for attempt in 1..=max_retries {
-                    match Handler::download(&target, &path, &filename, &cookie) {
+                // 每类错误有各自的重试次数，所有类别共享同一个attempt计数
+                for attempt in 1..=policy.max_attempts {
+                    // 按下Ctrl-C后不再开始新的下载，留给下次运行
+                    if interrupt::requested() {
+                        break;
+                    }
+                    match Handler::download(&page.image_url, &path, &page.filename, page.hash.as_deref(), &cookie) {
                         Ok(_) => {
                             success = true;
                             break;
                         }
+                        Err(DownloadError::Interrupted) => break,
                         Err(ref e) => {
+                            // 两次下载得到的内容相同但hash对不上，再下载也一样
+                            let stable_mismatch = match e {
+                                DownloadError::HashMismatch { actual, .. } => {
+                                    let same = last_mismatch.as_deref() == Some(actual.as_str());
+                                    last_mismatch = Some(actual.clone());
+                                    same
+                                }
+                                _ => false,
+                            };
+
                             // 判断是否应该重试 - 如果不需要判断只要失败就重试，只需要注释掉该代码即可。
-                            if !e.is_retryable() {
-                                println!(
-                                    "Non-retryable error for {}: {}",
-                                    filename, e
+                            if !e.is_retryable() || stable_mismatch {
+                                permanent = true;
+                                logger::event(
+                                    Level::Error,
+                                    "failed",
+                                    &format!("Non-retryable error for {}: {}", page.filename, e),
+                                    json!({ "file": page.filename, "attempt": attempt, "class": e.class(), "retryable": false, "error": e.to_string() }),
                                 );
+                                progress::failed(&page.filename, e);
                                 break; // 直接放弃，不进入重试列表
                             }
 
 
 
-                            if attempt == max_retries {
-                                println!(
-                                    "Failed after {} attempts: {} ({})",
-                                    max_retries, filename, e
+                            let max_retries = policy.budget(e.class());
+                            if attempt >= max_retries {
+                                logger::event(
+                                    Level::Error,
+                                    "failed",
+                                    &format!("Failed after {} attempts: {} ({})", attempt, page.filename, e),
+                                    json!({ "file": page.filename, "attempt": attempt, "class": e.class(), "retryable": true, "error": e.to_string() }),
                                 );
+                                progress::failed(&page.filename, e);
                                 break;
                             }
 
-                            let delay = compute_backoff(attempt as u32);
-                            println!(
-                                "[Attempt {}/{}] {} failed: {}. Retrying in {:?}",
-                                attempt, max_retries, filename, e, delay
+                            let delay = policy.delay(attempt, e.retry_after());
+                            logger::event(
+                                Level::Warn,
+                                "retry",
+                                &format!(
+                                    "[Attempt {}/{}] {} failed: {}. Retrying in {:?}",
+                                    attempt, max_retries, page.filename, e, delay
+                                ),
+                                json!({
+                                    "file": page.filename,
+                                    "attempt": attempt,
+                                    "class": e.class(),
+                                    "error": e.to_string(),
+                                    "delay_ms": delay.as_millis() as u64,
+                                }),
                             );
                             
-                            thread::sleep(delay);
+                            interrupt::sleep(delay);
                         }
                     }
                 }
 
-                if !success {
+                if permanent {
+                    gave_up.lock().unwrap().push(page.filename);
+                } else if !success {
                     let mut lock = failed_tasks.lock().unwrap();
-                    lock.push((target.clone(), filename.clone()));
+                    lock.push(page);
                 }
             });
         }
@@ -171,29 +1148,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .into_inner()
             .unwrap();
 
+        let gave_up_count = gave_up.lock().unwrap().len();
+        if retry_list.is_empty() && gave_up_count == 0 {
+            logger::info("All downloads completed successfully.");
+            break Outcome::Complete;
+        }
+
+        if interrupt::requested() {
+            break Outcome::Partial;
+        }
+
+        // 被封期间不再重试，保存进度后由调用者退出
+        if Handler::check_ban().is_err() {
+            break Outcome::Partial;
+        }
+
+        // 配额用完时重试也只会得到 509
+        if report::quota_exceeded() {
+            logger::error("Image quota exceeded, stop downloading.");
+            break Outcome::Partial;
+        }
+
         if retry_list.is_empty() {
-            println!("All downloads completed successfully.");
-            break;
+            logger::warn(&format!("{} pages failed with errors that retrying cannot fix.", gave_up_count));
+            break Outcome::Partial;
         }
 
         if !force_retry {
-            println!("Some downloads failed. Use -r to force retry.");
-            break;
+            logger::warn("Some downloads failed. Use -r to force retry.");
+            break Outcome::Partial;
         }
 
-        println!(
+        logger::info(&format!(
             "Retrying {} failed downloads after delay...",
             retry_list.len()
-        );
+        ));
+        let files: Vec<String> = retry_list.iter().map(|p| p.filename.clone()).collect();
+        progress::retrying(&files);
 
 
-        thread::sleep(Duration::from_secs(5));
+        interrupt::sleep(Duration::from_secs(5));
 
 
         pending_tasks = retry_list;
     }
-
-
-    Ok(())
-
 }
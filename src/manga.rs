@@ -1,172 +1,572 @@
 extern crate reqwest;
-use crate::handler::Handler;
+use crate::handler::{DownloadError, Handler};
+use crate::logger::{self, Level};
+use crate::mpv::Mpv;
+use crate::pages::PageSelection;
+use crate::progress;
+use crate::source;
 use core::cmp::max;
 use select::document::Document;
-use select::predicate::Name;
+use serde::Serialize;
+use serde_json::json;
+use select::predicate::{Attr, Class, Name, Predicate};
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
 
+/// 一张需要下载的图片
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub image_url: String,
+    /// 本地文件名，例如 "3729116-3.jpg"
+    pub filename: String,
+    /// 图片SHA-1的前10位(来自页面链接 /s/<hash>/)
+    /// 这是原图的hash，服务器提供的是缩小过的图片时为None，不做校验
+    pub hash: Option<String>,
+}
+
+/// 缩略图网格(#gdt)中的一项
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    /// 图片页面链接 .../s/<hash>/<id>-<n>
+    pub link: String,
+    /// 缩略图地址，普通缩略图是多张图片拼在一起的sprite
+    pub url: Option<String>,
+    /// 在sprite中的位置和大小，大缩略图是单独的图片，为None
+    pub crop: Option<Crop>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 图集评论(#cdiv 中的 div.c1)
+#[derive(Debug, Clone, Serialize)]
+pub struct Comment {
+    pub author: String,
+    /// 例如 "03 September 2015, 03:28"
+    pub posted: String,
+    /// 上传者的评论没有分数
+    pub score: Option<i32>,
+    pub uploader: bool,
+    pub text: String,
+    /// 评论中的链接(来源、汉化组等)
+    pub links: Vec<String>,
+}
+
 pub struct Manga {
     pub number: u32,
+    pub token: String,
+    pub title: String,
+    pub tags: Vec<String>,
+    /// 最后一个缩略图页的序号(?p=N，从0开始)
     pub pages: u32,
+    /// 每个缩略图页上的图片数量(取决于账号的缩略图设置)
+    pub per_page: u32,
+    /// 图集信息中的图片数量(Length: N pages)
+    pub length: Option<u32>,
     pub url: reqwest::Url,
+    /// 封面图片地址(#gd1)
+    pub cover: Option<String>,
+    /// 平均评分(Average: 4.52)
+    pub rating: Option<f32>,
+    /// 评分人数
+    pub rating_count: Option<u32>,
+    /// 收藏次数(Favorited: N times)
+    pub favorites: Option<u32>,
+    /// 压缩包下载地址(Archive Download 的 archiver.php)
+    pub archiver_url: Option<String>,
+    /// 图集页面上显示的评论，评论很多时只有上传者的评论和得分最高的一部分
+    pub comments: Vec<Comment>,
+    /// 父图集(Parent:)
+    pub parent: Option<reqwest::Url>,
+    /// "There are newer versions of this gallery available" 中列出的新版本，从旧到新
+    pub newer: Vec<reqwest::Url>,
 }
 
 impl Manga {
-    pub fn new(h: &Handler, url: &reqwest::Url) -> Self {
-        Manga {
-            number: Self::get_hentai_number(url),
-            pages: Self::get_page_number(h, url),
+    pub fn new(h: &Handler, url: &reqwest::Url) -> Result<Self, DownloadError> {
+        // hc=1 显示所有评论，默认只显示评分最高的几条，info.json 中需要完整的评论
+        let mut page = url.clone();
+        page.query_pairs_mut().append_pair("hc", "1");
+        let body = h.request("Get gallery page", page.as_str())?;
+        let doc = Document::from(body.as_str());
+
+        // 缩略图页的数量优先根据图片数量计算，分页栏可能是省略的("1 2 3 … 20")
+        let number = Self::get_hentai_number(url);
+        let per_page = Self::get_thumbnails(&doc, number).len() as u32;
+        let length = gdd_value(&doc, "Length:").and_then(|v| v.split_whitespace().next()?.parse().ok());
+        let pages = match length {
+            Some(n) if n > 0 && per_page > 0 => (n - 1) / per_page,
+            _ => Self::get_page_number(&doc),
+        };
+
+        Ok(Manga {
+            number,
+            token: Self::get_token(url),
+            title: Self::get_title(&doc),
+            tags: Self::get_tags(&doc),
+            pages,
+            per_page,
+            length,
             url: url.clone(),
-        }
+            cover: Self::get_cover(&doc),
+            rating: Self::get_rating(&doc),
+            rating_count: doc
+                .find(Attr("id", "rating_count"))
+                .next()
+                .and_then(|n| n.text().trim().replace(',', "").parse().ok()),
+            favorites: gdd_value(&doc, "Favorited:").and_then(|v| match v.as_str() {
+                "Never" => Some(0),
+                "Once" => Some(1),
+                v => v.split_whitespace().next()?.replace(',', "").parse().ok(),
+            }),
+            comments: Self::get_comments(&doc),
+            archiver_url: Self::get_archiver_url(&doc),
+            parent: Self::get_gallery_links(&doc, "gdd", url).into_iter().next(),
+            newer: Self::get_gallery_links(&doc, "gnd", url),
+        })
     }
     fn get_hentai_number(url: &reqwest::Url) -> u32 {
         let segs = url.path_segments().map(|c| c.collect::<Vec<_>>()).unwrap();
 
         segs[1].parse::<u32>().expect("Incorrect url")
     }
-    fn get_page_number(h: &Handler, url: &reqwest::Url) -> u32 {
-        // let mut pages = 0;
-        // let res = h
-        //     .request("Get Page number", &url[..])
-        //     .expect("Get page number failed");
-        // Document::from_read(res)
-        //     .expect("Document read response failed.")
-        //     .find(Name("a"))
-        //     .filter_map(|n| n.attr("href"))
-        //     .for_each(|x| {
-        //         if x.contains("?p=") {
-        //             let num = x.split("?p=").last();
-        //             pages = max(num.unwrap_or("0").parse::<u32>().unwrap_or(0), pages);
-        //         }
-        //     });
-        // pages
-
-
-
-        let res = h
-            .request("Get Page number", &url[..])
-            .expect("Get page number failed");
-
-        let status = res.status();
-        let body = res.text().expect("Failed to read body");
-
-        // 🔴 检查是否被封
-        if status.as_u16() == 503
-            || body.contains("This IP address has been temporarily banned due to an excessive request rate")
-        {
-            eprintln!("Your IP has been temporarily banned.");
-            eprintln!("Consider:");
-            eprintln!("  • Wait for a while and try again");
-            eprintln!("  • Switch IP and try again");
-            std::process::exit(1);
-        }
-
+    fn get_token(url: &reqwest::Url) -> String {
+        let segs = url.path_segments().map(|c| c.collect::<Vec<_>>()).unwrap();
 
-        let mut pages = 0;
+        segs.get(2).unwrap_or(&"").to_string()
+    }
+    // 图集标题在 <h1 id="gn"> 中
+    fn get_title(doc: &Document) -> String {
+        doc.find(Attr("id", "gn"))
+            .next()
+            .map(|n| n.text().trim().to_string())
+            .unwrap_or_default()
+    }
+    // 标签链接形如 <a id="ta_artist:some_name">，下划线代表空格
+    fn get_tags(doc: &Document) -> Vec<String> {
+        doc.find(Name("a"))
+            .filter_map(|n| n.attr("id"))
+            .filter_map(|id| id.strip_prefix("ta_"))
+            .map(|tag| tag.replace('_', " "))
+            .collect()
+    }
+    // 在指定id的容器中查找图集链接：#gdd 中是父图集，#gnd 中是更新的版本
+    fn get_gallery_links(doc: &Document, container: &str, url: &reqwest::Url) -> Vec<reqwest::Url> {
+        doc.find(Attr("id", container).descendant(Name("a")))
+            .filter_map(|n| n.attr("href"))
+            .filter_map(|href| url.join(href).ok())
+            .filter(|u| source::gallery_id(u).is_some())
+            .collect()
+    }
+    // 最后一个缩略图页的序号(?p=N)：取分页栏(table.ptt)中最大的 ?p=
+    // 没有分页栏时退回到页面中所有的链接
+    fn get_page_number(doc: &Document) -> u32 {
+        let max_p = |links: Vec<&str>| {
+            links
+                .iter()
+                .filter_map(|x| x.find("?p=").map(|pos| &x[pos + 3..]))
+                .filter_map(|num| num.parse::<u32>().ok())
+                .fold(0, max)
+        };
 
-        Document::from(body.as_str())
-            .find(Name("a"))
+        let pagination: Vec<&str> = doc
+            .find(Class("ptt").descendant(Name("a")))
             .filter_map(|n| n.attr("href"))
-            .for_each(|x| {
-                if let Some(pos) = x.find("?p=") {
-                    let num_str = &x[pos + 3..];
-                    if let Ok(num) = num_str.parse::<u32>() {
-                        pages = max(num, pages);
-                    }
-                }
-            });
-        pages
+            .collect();
+        if !pagination.is_empty() {
+            return max_p(pagination);
+        }
+        max_p(doc.find(Name("a")).filter_map(|n| n.attr("href")).collect())
+    }
+    // 封面在 #gd1 中：新版页面是带背景图的div，旧版是 <img>
+    fn get_cover(doc: &Document) -> Option<String> {
+        let gd1 = doc.find(Attr("id", "gd1")).next()?;
+        gd1.find(Name("div"))
+            .filter_map(|n| n.attr("style"))
+            .find_map(|style| background(style).map(|(url, _, _)| url))
+            .or_else(|| gd1.find(Name("img")).filter_map(|n| n.attr("src")).next().map(String::from))
+    }
+    // <a onclick="return popUp('https://e-hentai.org/archiver.php?gid=..&token=..',480,320)">Archive Download</a>
+    fn get_archiver_url(doc: &Document) -> Option<String> {
+        doc.find(Name("a"))
+            .filter_map(|n| n.attr("onclick"))
+            .filter(|onclick| onclick.contains("archiver.php"))
+            .find_map(|onclick| onclick.split('\'').nth(1).map(String::from))
+    }
+    // 平均评分在 <td id="rating_label">Average: 4.52</td> 中，没有人评分时为 "Not Yet Rated"
+    fn get_rating(doc: &Document) -> Option<f32> {
+        let label = doc.find(Attr("id", "rating_label")).next()?.text();
+        label.trim().strip_prefix("Average:")?.trim().parse().ok()
+    }
+    // 评论：div.c3 中是 "Posted on <date> by: <author>"，div.c5 中是分数，div.c6 中是正文
+    // 上传者的评论 id 为 comment_0
+    fn get_comments(doc: &Document) -> Vec<Comment> {
+        doc.find(Attr("id", "cdiv").descendant(Class("c1")))
+            .filter_map(|c| {
+                let body = c.find(Class("c6")).next()?;
+                let header = c.find(Class("c3")).next().map(|n| n.text()).unwrap_or_default();
+                let posted = header
+                    .split_once("Posted on ")
+                    .map(|(_, rest)| rest.split(" by:").next().unwrap_or(rest).trim().to_string())
+                    .unwrap_or_default();
+                let author = c
+                    .find(Class("c3").descendant(Name("a")))
+                    .next()
+                    .map(|a| a.text().trim().to_string())
+                    .unwrap_or_default();
+                let score = c
+                    .find(Class("c5").descendant(Name("span")))
+                    .next()
+                    .and_then(|n| n.text().trim().trim_start_matches('+').parse().ok());
+                Some(Comment {
+                    author,
+                    posted,
+                    score,
+                    uploader: body.attr("id") == Some("comment_0"),
+                    text: comment_text(&body).trim().to_string(),
+                    links: body.find(Name("a")).filter_map(|a| a.attr("href")).map(String::from).collect(),
+                })
+            })
+            .collect()
     }
 
-    /// Returns (image_url, filename)
     /// filename is extracted from page URL (e.g., "3729116-3" from ".../s/xxx/3729116-3")
     /// with extension from the actual image URL
-    fn get_image_link(url: &str, h: Handler) -> (String, String) {
+    fn get_image_link(url: &str, h: Handler) -> Result<Page, DownloadError> {
         let tmp = match &(h.host.to_string())[..] {
             "exhentai.org" => "exhentai",
             "e-hentai.org" => "ehgt",
             _ => panic!("shound not happend"),
         };
-        let res = h
-            .request("Get image link", url)
-            .expect("Get image link failed");
+        let res = h.request("Get image link", url)?;
+        let doc = Document::from(res.as_str());
 
         let mut ret: Option<String> = None;
-        Document::from_read(res)
-            .unwrap()
-            .find(Name("img"))
+        doc.find(Name("img"))
             .filter_map(|n| n.attr("src"))
             .for_each(|x| {
                 if !x.contains(tmp) {
                     ret = Some(x.to_string());
-                    println!("Find {}", x);
                 }
             });
 
         let image_url = match ret {
             Some(x) => x,
-            None => panic!("Get image Failed with url: {}", url),
+            None => return Err(DownloadError::Parse(format!("Get image Failed with url: {}", url))),
         };
 
-        // Extract filename from page URL (last segment, e.g., "3729116-3")
-        let base_name = url
-            .trim_end_matches('/')
-            .split('/')
-            .last()
-            .unwrap_or("unknown");
-
-        // Extract extension from image URL
-        let extension = image_url
-            .split('/')
-            .last()
-            .and_then(|s| s.split('.').last())
-            .unwrap_or("jpg");
+        // 原图比显示的分辨率大时，页面上有 "Download original" (fullimg) 链接，显示的是缩小过的图片
+        let resampled = doc
+            .find(Name("a"))
+            .filter_map(|n| n.attr("href"))
+            .any(|href| href.contains("/fullimg"));
+        page_from_image(url, image_url, !resampled)
+    }
 
-        let filename = format!("{}.{}", base_name, extension);
-        (image_url, filename)
+    /// 遍历缩略图页(?p=N)，收集每张图片的页面链接(.../s/<hash>/<id>-<n>)
+    /// 指定了 selection 时只打开包含所选页面的缩略图页
+    pub fn get_page_links(&self, h: &Handler, selection: Option<&PageSelection>) -> Result<Vec<String>, DownloadError> {
+        Ok(self
+            .get_page_thumbnails(h, selection)?
+            .into_iter()
+            .map(|t| t.link)
+            .collect())
     }
 
-    /// Returns Vec<(image_url, filename)>
-    pub fn get_download_urls<'a>(&self, h: &'a Handler) -> Vec<(String, String)> {
+    /// 和 get_page_links 相同，同时保留每一页的缩略图
+    pub fn get_page_thumbnails(&self, h: &Handler, selection: Option<&PageSelection>) -> Result<Vec<Thumbnail>, DownloadError> {
         let url = &self.url;
-        let pages = self.pages;
+        let thumbnail_pages: Vec<u32> = match selection {
+            Some(sel) => sel.thumbnail_pages(self.per_page, self.pages).into_iter().collect(),
+            None => (0..self.pages + 1).collect(),
+        };
 
-        let download_urls = Arc::new(Mutex::new(vec![]));
+        let mut thumbnails: Vec<Thumbnail> = vec![];
+        for i in thumbnail_pages {
+            let download_url = url.join(&format!("?p={}", i)).unwrap();
+            let res = h.request("Get each page", download_url.as_str())?;
+            let found = Self::get_thumbnails(&Document::from(res.as_str()), self.number);
+            if found.is_empty() {
+                logger::warn(&format!("No thumbnails found on {}", download_url));
+            }
+            thumbnails.extend(found);
+        }
 
-        for i in 0..pages + 1 {
-            let pool = ThreadPool::new(8);
+        if let Some(sel) = selection {
+            thumbnails.retain(|t| page_index(&t.link).is_some_and(|n| sel.contains(n)));
+        }
 
-            let download_url = url.join(&format!("?p={}", i)).unwrap();
-            let res = h
-                .request("Get each page", &download_url.as_str())
-                .expect("Get each page failed");
-
-            let mut links: Vec<String> = vec![];
-            Document::from_read(res)
-                .expect("Document read response failed.")
-                .find(Name("a"))
-                .filter_map(|n| n.attr("href"))
-                .for_each(|x| {
-                    if x.contains("s/") {
-                        links.push(x.to_string());
-                    }
-                });
-
-            for link in links {
-                let cloned_v = download_urls.clone();
-                let g = h.clone();
-                pool.execute(move || {
-                    let (image_url, filename) = Manga::get_image_link(&link, g);
-                    cloned_v.lock().unwrap().push((image_url, filename));
-                });
+        // 解析出的图片数量应该和图集信息中的一致，不一致说明缩略图页没有抓全或者抓到了无关的链接
+        if let Some(length) = self.length {
+            let expected = match selection {
+                Some(sel) => (1..=length).filter(|n| sel.contains(*n)).count(),
+                None => length as usize,
+            };
+            if thumbnails.len() != expected {
+                logger::warn(&format!(
+                    "Gallery {} declares {} pages, expected {} links but found {}",
+                    self.number,
+                    length,
+                    expected,
+                    thumbnails.len()
+                ));
+            }
+        }
+        Ok(thumbnails)
+    }
+
+    // 缩略图网格 #gdt 中的图片页面链接，按照页面中的顺序
+    // 普通缩略图为 div.gdtm > div > a，大缩略图为 div.gdtl > a，新版页面为 #gdt > a > div
+    // 只保留 /s/<hash>/<gid>-<n> 形式且属于当前图集的链接，同一页面的重复链接(图片和标题)只保留一个
+    fn get_thumbnails(doc: &Document, gid: u32) -> Vec<Thumbnail> {
+        let mut thumbnails: Vec<Thumbnail> = vec![];
+        for a in doc.find(Attr("id", "gdt").descendant(Name("a"))) {
+            let href = match a.attr("href") {
+                Some(href) => href,
+                None => continue,
+            };
+            let is_page = page_hash(href).is_some()
+                && page_index(href).is_some()
+                && page_name(href).split_once('-').is_some_and(|(id, _)| id == gid.to_string());
+            if !is_page || thumbnails.iter().any(|t| t.link == href) {
+                continue;
             }
 
-            pool.join();
+            // sprite 作为背景图：新版在链接内的div上，旧版普通缩略图在链接外层的div上
+            let styled = a
+                .find(Name("div"))
+                .chain(a.parent())
+                .filter_map(|n| n.attr("style"))
+                .find_map(|style| background(style).map(|bg| (bg, style)));
+            let (url, crop) = match styled {
+                Some(((url, x, y), style)) => {
+                    let crop = match (css_px(style, "width"), css_px(style, "height")) {
+                        (Some(width), Some(height)) => Some(Crop { x, y, width, height }),
+                        _ => None,
+                    };
+                    (Some(url), crop)
+                }
+                // 旧版大缩略图：<img src> 就是单独的缩略图
+                None => (
+                    a.find(Name("img"))
+                        .filter_map(|n| n.attr("src"))
+                        .find(|src| !src.ends_with("blank.gif"))
+                        .map(String::from),
+                    None,
+                ),
+            };
+
+            thumbnails.push(Thumbnail {
+                link: href.to_string(),
+                url,
+                crop,
+            });
         }
+        thumbnails
+    }
+
+    /// 并发打开每个图片页面(或者通过MPV的imagedispatch)，解析出真正的图片地址
+    /// 解析失败的页面不会出现在结果中
+    pub fn resolve_links(h: &Handler, links: Vec<String>, concurrency: usize, mpv: Option<Arc<Mpv>>) -> Vec<Page> {
+        let download_urls = Arc::new(Mutex::new(vec![]));
+        let pool = ThreadPool::new(concurrency);
+
+        for link in links {
+            let cloned_v = download_urls.clone();
+            let g = h.clone();
+            let mpv = mpv.clone();
+            pool.execute(move || {
+                let result = match &mpv {
+                    Some(mpv) => mpv.resolve(&link),
+                    None => Manga::get_image_link(&link, g),
+                };
+                match result {
+                    // 按下Ctrl-C后剩下的页面留给下次运行
+                    Err(DownloadError::Interrupted) => {}
+                    Ok(page) => {
+                        logger::event(
+                            Level::Info,
+                            "resolved",
+                            &format!("Resolved {} -> {}", page.filename, page.image_url),
+                            json!({ "file": page.filename, "page": link, "url": page.image_url }),
+                        );
+                        cloned_v.lock().unwrap().push(page)
+                    }
+                    Err(e) => {
+                        logger::event(
+                            Level::Error,
+                            "failed",
+                            &format!("Resolve {} failed: {}", link, e),
+                            json!({ "page": link, "class": e.class(), "retryable": e.is_retryable(), "error": e.to_string() }),
+                        );
+                        progress::failed(page_name(&link), &e);
+                    }
+                }
+            });
+        }
+
+        pool.join();
 
         let lock = Arc::try_unwrap(download_urls).expect("Lock still has multiple owners");
-        let urls = lock.into_inner().expect("Mutex cannot be locked");
-        urls
+        lock.into_inner().expect("Mutex cannot be locked")
+    }
+}
+
+// 图集信息表(#gdd)中某一行的值，例如 "Length:" -> "20 pages"
+fn gdd_value(doc: &Document, label: &str) -> Option<String> {
+    doc.find(Attr("id", "gdd").descendant(Name("tr"))).find_map(|row| {
+        let cells: Vec<String> = row.find(Name("td")).map(|td| td.text()).collect();
+        match cells.as_slice() {
+            [l, value, ..] if l.trim() == label => Some(value.trim().to_string()),
+            _ => None,
+        }
+    })
+}
+
+// 评论正文，<br> 转换为换行
+fn comment_text(node: &select::node::Node) -> String {
+    node.children()
+        .map(|child| match child.name() {
+            Some("br") => "\n".to_string(),
+            Some(_) => comment_text(&child),
+            None => child.as_text().unwrap_or_default().to_string(),
+        })
+        .collect()
+}
+
+// style 中的 background: ... url(<url>) -<x>px -<y>px，返回地址和在sprite中的偏移
+fn background(style: &str) -> Option<(String, u32, u32)> {
+    let start = style.find("url(")? + 4;
+    let end = start + style[start..].find(')')?;
+    let url = style[start..end].trim_matches(|c| c == '\'' || c == '"').to_string();
+    let mut offsets = style[end + 1..]
+        .split_whitespace()
+        .map(|v| v.trim_end_matches(';').trim_end_matches("px").trim_start_matches('-').parse::<u32>().ok());
+    let x = offsets.next().flatten().unwrap_or(0);
+    let y = offsets.next().flatten().unwrap_or(0);
+    Some((url, x, y))
+}
+
+// style 中 "width:100px" 这样的像素值
+fn css_px(style: &str, name: &str) -> Option<u32> {
+    style.split(';').find_map(|decl| {
+        let (key, value) = decl.split_once(':')?;
+        if key.trim() != name {
+            return None;
+        }
+        value.trim().trim_end_matches("px").parse().ok()
+    })
+}
+
+/// 图片页面链接 .../s/<hash>/<id>-<n> 中的hash(图片SHA-1的前10位)
+pub fn page_hash(link: &str) -> Option<&str> {
+    let mut segs = link.trim_end_matches('/').rsplit('/');
+    let _name = segs.next()?;
+    let hash = segs.next()?;
+    match segs.next() {
+        Some("s") => Some(hash),
+        _ => None,
+    }
+}
+
+/// 根据页面链接和解析出的图片地址生成 Page
+/// filename 为页面链接的最后一段(例如 "3729116-3")加上图片地址中的扩展名
+/// original 为false(图片是缩小过的)时不保留hash，链接中的hash只对原图有效
+pub fn page_from_image(link: &str, image_url: String, original: bool) -> Result<Page, DownloadError> {
+    // 图片配额用完时图片页显示的是 509.gif
+    if image_url.ends_with("/509.gif") {
+        return Err(DownloadError::quota_exceeded());
+    }
+
+    let extension = image_url
+        .split('/')
+        .next_back()
+        .and_then(|s| s.split('.').next_back())
+        .unwrap_or("jpg");
+
+    let filename = format!("{}.{}", page_name(link), extension);
+    Ok(Page {
+        image_url,
+        filename,
+        hash: page_hash(link).filter(|_| original).map(String::from),
+    })
+}
+
+/// 图片在图集中的页码(从1开始)，即页面链接最后一段 <id>-<n> 中的 n
+pub fn page_index(link: &str) -> Option<u32> {
+    page_name(link).rsplit('-').next()?.parse().ok()
+}
+
+/// 图片页面链接的最后一段(<id>-<n>)，同时也是本地文件名(不含扩展名)
+pub fn page_name(link: &str) -> &str {
+    link.trim_end_matches('/')
+        .split('/')
+        .next_back()
+        .unwrap_or("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GID: u32 = 3729116;
+
+    fn thumbnails(html: &str) -> Vec<Thumbnail> {
+        Manga::get_thumbnails(&Document::from(html), GID)
+    }
+
+    fn links(thumbnails: &[Thumbnail]) -> Vec<&str> {
+        thumbnails.iter().map(|t| t.link.as_str()).collect()
+    }
+
+    const PAGES: [&str; 3] = [
+        "https://e-hentai.org/s/0a1b2c3d4e/3729116-1",
+        "https://e-hentai.org/s/1b2c3d4e5f/3729116-2",
+        "https://e-hentai.org/s/2c3d4e5f6a/3729116-3",
+    ];
+
+    #[test]
+    fn thumbnails_gdtm() {
+        let found = thumbnails(include_str!("../tests/fixtures/gdt_gdtm.html"));
+        // 评论中的 /s/ 链接不在 #gdt 中
+        assert_eq!(links(&found), PAGES);
+
+        let sprite = "https://ehgt.org/m/003729/3729116-00.jpg";
+        assert!(found.iter().all(|t| t.url.as_deref() == Some(sprite)));
+        assert_eq!(found[1].crop, Some(Crop { x: 100, y: 0, width: 100, height: 142 }));
+        assert_eq!(found[2].crop, Some(Crop { x: 200, y: 0, width: 100, height: 140 }));
+    }
+
+    #[test]
+    fn thumbnails_gdtl() {
+        let found = thumbnails(include_str!("../tests/fixtures/gdt_gdtl.html"));
+        // 标题链接和图片链接指向同一页面，其他图集的页面不算
+        assert_eq!(links(&found), PAGES);
+
+        assert!(found.iter().all(|t| t.crop.is_none()));
+        assert!(found[0].url.as_deref().is_some_and(|u| u.ends_with("-1280-1810-jpg_l.jpg")));
+    }
+
+    #[test]
+    fn thumbnails_current_layout() {
+        let found = thumbnails(include_str!("../tests/fixtures/gdt_current.html"));
+        // 翻页链接、重复的第1页和没有页码的链接都被忽略
+        assert_eq!(links(&found), PAGES);
+
+        let sprite = "https://ehgt.org/w/01/372/91160-abc123.webp";
+        assert!(found.iter().all(|t| t.url.as_deref() == Some(sprite)));
+        assert_eq!(found[0].crop, Some(Crop { x: 0, y: 0, width: 100, height: 144 }));
+        assert_eq!(found[2].crop, Some(Crop { x: 200, y: 0, width: 100, height: 139 }));
+    }
+
+    #[test]
+    fn thumbnails_other_gallery() {
+        let found = Manga::get_thumbnails(&Document::from(include_str!("../tests/fixtures/gdt_gdtl.html")), 1234567);
+        assert_eq!(links(&found), ["https://e-hentai.org/s/5e5e5e5e5e/1234567-2"]);
     }
 }
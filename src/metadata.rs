@@ -0,0 +1,48 @@
+// 图集目录中的 info.json：保存图集信息、评分和评论
+// 图集之后被删除时，本地仍然保留来源链接和汉化组等信息
+
+use crate::manga::{Comment, Manga};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+pub const FILENAME: &str = "info.json";
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    id: u32,
+    token: &'a str,
+    title: &'a str,
+    url: String,
+    tags: &'a [String],
+    length: Option<u32>,
+    parent: Option<String>,
+    newer: Vec<String>,
+    rating: Option<f32>,
+    rating_count: Option<u32>,
+    favorites: Option<u32>,
+    comments: &'a [Comment],
+}
+
+/// 每次下载都重新写入，评分和评论保持最新
+pub fn write(m: &Manga, dir: &str) -> std::io::Result<()> {
+    let metadata = Metadata {
+        id: m.number,
+        token: &m.token,
+        title: &m.title,
+        url: m.url.to_string(),
+        tags: &m.tags,
+        length: m.length,
+        parent: m.parent.as_ref().map(|u| u.to_string()),
+        newer: m.newer.iter().map(|u| u.to_string()).collect(),
+        rating: m.rating,
+        rating_count: m.rating_count,
+        favorites: m.favorites,
+        comments: &m.comments,
+    };
+    let text = serde_json::to_string_pretty(&metadata)?;
+    let path = Path::new(dir).join(FILENAME);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, &path)
+}
@@ -0,0 +1,140 @@
+// 多页查看器(MPV)：/mpv/<gid>/<token>/ 一个页面中就包含了所有图片的key
+// 之后通过 api.php 的 imagedispatch 直接得到图片地址，不需要再打开每一个 /s/ 页面
+// 只有在账号设置中开启了MPV(或者有对应的权限)时才能使用
+
+use crate::handler::{DownloadError, Handler};
+use crate::logger;
+use crate::manga::{self, Manga, Page};
+use crate::pages::PageSelection;
+use serde::Deserialize;
+use serde_json::json;
+
+/// 解析图片地址的方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolver {
+    /// 先尝试MPV，不可用时使用普通的缩略图页
+    Auto,
+    Mpv,
+    /// 缩略图页 + 每张图片的 /s/ 页面
+    Pages,
+}
+
+// imagelist 中的一项，另外还有原始文件名(n)和缩略图(t)
+#[derive(Debug, Deserialize)]
+struct MpvImage {
+    /// imgkey，和 /s/<key>/ 中的相同
+    k: String,
+}
+
+pub struct Mpv {
+    gid: u32,
+    mpvkey: String,
+    /// 按照页码排列的imgkey
+    keys: Vec<String>,
+    host: String,
+    api: Handler,
+}
+
+impl Mpv {
+    /// 打开MPV页面，没有开启MPV时返回None
+    pub fn open(h: &Handler, m: &Manga, cookie: &str) -> Result<Option<Self>, DownloadError> {
+        let url = m
+            .url
+            .join(&format!("/mpv/{}/{}/", m.number, m.token))
+            .map_err(|e| DownloadError::Parse(e.to_string()))?;
+        let body = match h.request("Get MPV page", url.as_str()) {
+            Ok(body) => body,
+            // 没有权限时可能返回403/404
+            Err(DownloadError::Request(e)) if e.status().is_some_and(|s| s.is_client_error()) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mpvkey: String = match script_value(&body, "mpvkey") {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let images: Vec<MpvImage> = match script_value(&body, "imagelist") {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+
+        let api_host = match h.host.as_str() {
+            "exhentai.org" => "exhentai.org",
+            _ => "api.e-hentai.org",
+        };
+
+        if let Some(length) = m.length {
+            if images.len() != length as usize {
+                logger::warn(&format!(
+                    "Gallery {} declares {} pages but the MPV page lists {}",
+                    m.number,
+                    length,
+                    images.len()
+                ));
+            }
+        }
+
+        Ok(Some(Mpv {
+            gid: m.number,
+            mpvkey,
+            keys: images.into_iter().map(|img| img.k).collect(),
+            host: h.host.clone(),
+            // api.e-hentai.org 的请求和图集页面共用同一个限速
+            api: Handler::new(api_host, cookie).limited_as(&h.host),
+        }))
+    }
+
+    /// 和缩略图页中相同格式的页面链接 https://<host>/s/<key>/<gid>-<n>
+    /// 这样复用、图集库中的hash等都不需要区分来源
+    pub fn page_links(&self, selection: Option<&PageSelection>) -> Vec<String> {
+        self.keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (i as u32 + 1, key))
+            .filter(|(n, _)| selection.is_none_or(|sel| sel.contains(*n)))
+            .map(|(n, key)| format!("https://{}/s/{}/{}-{}", self.host, key, self.gid, n))
+            .collect()
+    }
+
+    /// 通过 imagedispatch 解析页面链接对应的图片地址
+    pub fn resolve(&self, link: &str) -> Result<Page, DownloadError> {
+        let (page, key) = match (manga::page_index(link), manga::page_hash(link)) {
+            (Some(page), Some(key)) => (page, key),
+            _ => return Err(DownloadError::Parse(format!("Not a page link: {}", link))),
+        };
+
+        let url = format!("https://{}/api.php", self.api.host);
+        let res = self.api.post_json(
+            &url,
+            &json!({
+                "method": "imagedispatch",
+                "gid": self.gid,
+                "page": page,
+                "imgkey": key,
+                "mpvkey": self.mpvkey,
+            }),
+        )?;
+
+        let image_url = match res.get("i").and_then(|v| v.as_str()) {
+            Some(i) if !i.is_empty() => i.to_string(),
+            _ => {
+                let error = res.get("error").and_then(|v| v.as_str()).unwrap_or("no image url");
+                return Err(DownloadError::Parse(format!("imagedispatch for {} failed: {}", link, error)));
+            }
+        };
+        // lf 是 "Download original" 的链接，只有图片被缩小过时才有
+        let resampled = res.get("lf").and_then(|v| v.as_str()).is_some_and(|lf| !lf.is_empty());
+        manga::page_from_image(link, image_url, !resampled)
+    }
+}
+
+// 页面脚本中的 var <name> = <json>; 只解析等号后面的第一个JSON值
+fn script_value<T: serde::de::DeserializeOwned>(body: &str, name: &str) -> Option<T> {
+    let start = body.find(&format!("var {}", name))?;
+    let rest = &body[start..];
+    let rest = rest[rest.find('=')? + 1..].trim_start();
+    serde_json::Deserializer::from_str(rest)
+        .into_iter::<T>()
+        .next()?
+        .ok()
+}
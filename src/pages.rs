@@ -0,0 +1,69 @@
+// --pages：只下载图集中的部分页面，例如 "1-20,35,100-"
+// 页码从1开始，和图片页面链接 /s/<hash>/<id>-<n> 中的 n 相同
+
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSelection {
+    /// (起始页, 结束页)，结束页为None表示到最后一页
+    ranges: Vec<(u32, Option<u32>)>,
+}
+
+impl PageSelection {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut ranges = vec![];
+        for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let range = match part.split_once('-') {
+                Some((start, end)) => {
+                    let start = match start.trim() {
+                        "" => 1,
+                        s => parse_page(s)?,
+                    };
+                    let end = match end.trim() {
+                        "" => None,
+                        e => Some(parse_page(e)?),
+                    };
+                    if end.is_some_and(|e| e < start) {
+                        return Err(format!("range '{}' ends before it starts", part));
+                    }
+                    (start, end)
+                }
+                None => {
+                    let n = parse_page(part)?;
+                    (n, Some(n))
+                }
+            };
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            return Err("no pages selected".to_string());
+        }
+        Ok(PageSelection { ranges })
+    }
+
+    pub fn contains(&self, page: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| page >= start && end.is_none_or(|e| page <= e))
+    }
+
+    /// 需要打开的缩略图页(?p=N，从0开始)
+    /// per_page 为每页的缩略图数量，last 为最后一个缩略图页
+    pub fn thumbnail_pages(&self, per_page: u32, last: u32) -> BTreeSet<u32> {
+        let per_page = per_page.max(1);
+        let mut pages = BTreeSet::new();
+        for &(start, end) in &self.ranges {
+            let first = (start - 1) / per_page;
+            let end = end.map_or(last, |e| ((e - 1) / per_page).min(last));
+            pages.extend(first..=end);
+        }
+        pages
+    }
+}
+
+fn parse_page(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("'{}' is not a page number (pages start from 1)", value)),
+    }
+}
@@ -1,18 +1,80 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 use std::fs;
+use std::time::Duration;
 // use url::{Host, Url};
 
 //kimi
 use reqwest::Url;
 use url::Host;
 
+use crate::config::Settings;
+use crate::logger::{Format, Level};
+use crate::mpv::Resolver;
+use crate::pages::PageSelection;
+use crate::quota::QuotaStrategy;
+use crate::retry::RetryPolicy;
+use crate::source::Source;
+
 
 
 #[derive(Debug)]
 pub struct Cli {
-    pub url: Url,
+    pub url: Option<Url>,
+    pub source: Option<Source>,
+    pub limit: Option<usize>,
+    /// --pages 选择的页面，None表示全部
+    pub pages: Option<PageSelection>,
     pub cookie: String,
+    pub library: PathBuf,
     pub retry: bool,
+    pub deep_verify: bool,
+    /// 重新下载所有页面
+    pub force: bool,
+    /// 图片配额用完时的处理方式
+    pub on_quota: QuotaStrategy,
+    /// --on-quota wait 时检查配额的间隔
+    pub quota_poll: Duration,
+    /// 下载种子文件代替图片
+    pub torrent: bool,
+    /// 打开种子文件的外部命令
+    pub torrent_client: Option<String>,
+    /// 保存封面
+    pub cover: bool,
+    /// 保存每一页的缩略图
+    pub thumbnails: bool,
+    pub wait_on_ban: bool,
+
+    /// 每个主机每秒的HTML页面请求数
+    pub rate: f64,
+    /// 所有H@H节点共享的每秒图片请求数，任一节点返回429/503都会让它减速
+    pub image_rate: f64,
+    /// 单独指定速度的主机
+    pub host_rates: HashMap<String, f64>,
+    /// 同时下载的线程数
+    pub concurrency: usize,
+
+    /// 解析图片地址的方式
+    pub resolver: Resolver,
+
+    /// 建立连接的超时时间
+    pub connect_timeout: Duration,
+    /// 单个请求的总超时时间
+    pub timeout: Duration,
+    /// 下载图片时的最低速度(字节/秒)，0表示不检测
+    pub stall_speed: u64,
+    /// 低速检测的时间窗口
+    pub stall_time: Duration,
+
+    /// 配置文件路径，watch 子命令从中读取保存的搜索
+    pub config: PathBuf,
+    pub retry_policy: RetryPolicy,
+
+    pub log_level: Level,
+    pub log_format: Format,
+    pub log_file: Option<PathBuf>,
+    /// 运行报告的路径，- 表示stdout
+    pub report: Option<PathBuf>,
 
     pub proxy_mode: ProxyMode,
     pub proxy: Option<String>,
@@ -30,25 +92,33 @@ pub enum ProxyMode {
 
 pub fn parse_cli(matches: &clap::ArgMatches) -> Cli {
     // parse url: String to download_url: url::Url
-    let url: String = matches
-        .value_of("url")
-        .expect("Should provide the url.")
-        .parse::<String>()
-        .expect("Incorrect url.");
+    // 使用子命令(如watch)时可以不提供url
+    let (download_url, source) = match matches.value_of("url") {
+        Some(url) => {
+            let (u, s) = parse_url(url);
+            (Some(u), Some(s))
+        }
+        None => (None, None),
+    };
 
-    let download_url: Url = Url::parse(url.trim()).expect("Parse url failed");
-    assert!(download_url.scheme() == "https");
-    assert!(
-        download_url.host() == Some(Host::Domain("e-hentai.org"))
-            || download_url.host() == Some(Host::Domain("exhentai.org"))
-    );
+    let limit = matches
+        .value_of("limit")
+        .map(|n| n.trim().parse::<usize>().expect("Incorrect limit, should be a number."));
+
+    let pages = matches.value_of("pages").map(|value| match PageSelection::parse(value) {
+        Ok(sel) => sel,
+        Err(e) => {
+            eprintln!("Error: incorrect pages '{}': {}", value, e);
+            std::process::exit(1);
+        }
+    });
 
 
     // read cookie file into cookie: String
     let mut cookie = String::from("");
     if let Some(c) = matches.value_of("cookie") {
         if Path::new(&c).exists() {
-            cookie = fs::read_to_string(&c)
+            cookie = fs::read_to_string(c)
                 .expect("Something went wrong reading the cookie file")
                 .trim()
                 .to_string();
@@ -57,10 +127,130 @@ pub fn parse_cli(matches: &clap::ArgMatches) -> Cli {
 
 
 
+    let library = PathBuf::from(matches.value_of("library").unwrap_or("library.json"));
+
+
     // -------------------------
     // 3️⃣ retry
     // -------------------------
     let retry = matches.is_present("retry");
+    let deep_verify = matches.is_present("deep-verify");
+    let force = matches.is_present("force");
+    let on_quota = QuotaStrategy::parse(matches.value_of("on-quota").unwrap_or("stop")).unwrap_or(QuotaStrategy::Stop);
+    let quota_poll = match matches.value_of("quota-poll") {
+        Some(v) => match v.trim().parse::<u64>() {
+            Ok(n) if n > 0 => Duration::from_secs(n),
+            _ => {
+                eprintln!("Error: --quota-poll should be a positive number of seconds");
+                std::process::exit(1);
+            }
+        },
+        None => Duration::from_secs(300),
+    };
+    let torrent = matches.is_present("torrent");
+    let torrent_client = matches.value_of("torrent-client").map(String::from);
+    let cover = matches.is_present("cover");
+    let thumbnails = matches.is_present("thumbnails");
+    let wait_on_ban = matches.is_present("wait-on-ban");
+
+
+    // -------------------------
+    // 限速
+    // -------------------------
+    let rate = parse_rate(matches.value_of("rate").unwrap_or("2"));
+    let image_rate = parse_rate(matches.value_of("image-rate").unwrap_or("8"));
+    let mut host_rates = HashMap::new();
+    if let Some(values) = matches.values_of("host-rate") {
+        for value in values {
+            match value.split_once('=') {
+                Some((host, r)) => {
+                    host_rates.insert(host.trim().to_string(), parse_rate(r));
+                }
+                None => {
+                    eprintln!("Error: incorrect host rate '{}', should be <host>=<n>", value);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    // -------------------------
+    // 配置文件 + 重试策略
+    // -------------------------
+    let config = PathBuf::from(matches.value_of("config").unwrap_or("hentai-downloader.toml"));
+    let settings = if config.exists() || matches.occurrences_of("config") > 0 {
+        match Settings::load(&config) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error: load config {} failed: {}", config.display(), e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Settings::default()
+    };
+
+    let mut retry_policy = settings.retry;
+    if let Some(n) = matches.value_of("max-retries") {
+        retry_policy.max_attempts = parse_number(n, "max-retries") as u32;
+    }
+    if let Some(n) = matches.value_of("retry-base") {
+        retry_policy.base_ms = parse_number(n, "retry-base");
+    }
+    if let Some(n) = matches.value_of("retry-cap") {
+        retry_policy.cap_ms = parse_number(n, "retry-cap");
+    }
+    if let Some(n) = matches.value_of("retry-jitter") {
+        retry_policy.jitter_ms = parse_number(n, "retry-jitter");
+    }
+    if retry_policy.max_attempts == 0 {
+        eprintln!("Error: --max-retries (retry.max_attempts) should be at least 1");
+        std::process::exit(1);
+    }
+    if let Some(values) = matches.values_of("retry-budget") {
+        for value in values {
+            let result = match value.split_once('=') {
+                Some((class, n)) => retry_policy.set_budget(class.trim(), parse_number(n, "retry-budget") as u32),
+                None => Err("should be <class>=<n>".to_string()),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: incorrect retry budget '{}': {}", value, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let concurrency = matches
+        .value_of("concurrency")
+        .unwrap_or("16")
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| *n > 0)
+        .expect("Incorrect concurrency, should be a positive number.");
+
+    let resolver = match matches.value_of("resolver").unwrap_or("auto") {
+        "mpv" => Resolver::Mpv,
+        "pages" => Resolver::Pages,
+        _ => Resolver::Auto,
+    };
+
+    let connect_timeout = Duration::from_secs(parse_number(matches.value_of("connect-timeout").unwrap_or("15"), "connect-timeout"));
+    let timeout = Duration::from_secs(parse_number(matches.value_of("timeout").unwrap_or("300"), "timeout"));
+    if timeout.is_zero() {
+        eprintln!("Error: --timeout should be at least 1 second");
+        std::process::exit(1);
+    }
+    let stall_speed = parse_number(matches.value_of("stall-speed").unwrap_or("4"), "stall-speed") * 1024;
+    let stall_time = Duration::from_secs(parse_number(matches.value_of("stall-time").unwrap_or("30"), "stall-time"));
+
+
+    let log_level = Level::from_verbosity(matches.occurrences_of("verbose"), matches.occurrences_of("quiet"));
+    let log_format = match matches.value_of("log-format").unwrap_or("text") {
+        "json" => Format::Json,
+        _ => Format::Text,
+    };
+    let log_file = matches.value_of("log-file").map(PathBuf::from);
+    let report = matches.value_of("report").map(PathBuf::from);
 
 
     // -------------------------
@@ -107,10 +297,125 @@ pub fn parse_cli(matches: &clap::ArgMatches) -> Cli {
 
     Cli {
         url: download_url,
+        source,
+        limit,
+        pages,
         cookie,
+        library,
         retry,
+        deep_verify,
+        force,
+        on_quota,
+        quota_poll,
+        torrent,
+        torrent_client,
+        cover,
+        thumbnails,
+        wait_on_ban,
+        rate,
+        image_rate,
+        host_rates,
+        concurrency,
+        resolver,
+        connect_timeout,
+        timeout,
+        stall_speed,
+        stall_time,
+        config,
+        retry_policy,
+        log_level,
+        log_format,
+        log_file,
+        report,
         proxy_mode,
         proxy,
         convert_socks5h,
     }
 }
+
+
+/// 不读取命令行、cookie和配置文件的默认设置，和 cli.yml 中的默认值一致
+#[cfg(test)]
+pub fn defaults() -> Cli {
+    Cli {
+        url: None,
+        source: None,
+        limit: None,
+        pages: None,
+        cookie: String::new(),
+        library: PathBuf::from("library.json"),
+        retry: false,
+        deep_verify: false,
+        force: false,
+        on_quota: QuotaStrategy::Stop,
+        quota_poll: Duration::from_secs(300),
+        torrent: false,
+        torrent_client: None,
+        cover: false,
+        thumbnails: false,
+        wait_on_ban: false,
+        rate: 2.0,
+        image_rate: 8.0,
+        host_rates: HashMap::new(),
+        concurrency: 16,
+        resolver: Resolver::Auto,
+        connect_timeout: Duration::from_secs(15),
+        timeout: Duration::from_secs(300),
+        stall_speed: 4 * 1024,
+        stall_time: Duration::from_secs(30),
+        config: PathBuf::from("hentai-downloader.toml"),
+        retry_policy: RetryPolicy::default(),
+        log_level: Level::from_verbosity(0, 0),
+        log_format: Format::Text,
+        log_file: None,
+        report: None,
+        proxy_mode: ProxyMode::None,
+        proxy: None,
+        convert_socks5h: false,
+    }
+}
+
+
+fn parse_number(value: &str, name: &str) -> u64 {
+    match value.trim().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("Error: incorrect {} '{}', should be a number", name, value);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+fn parse_rate(value: &str) -> f64 {
+    match value.trim().parse::<f64>() {
+        Ok(r) if r > 0.0 => r,
+        _ => {
+            eprintln!("Error: incorrect rate '{}', should be a positive number of requests per second", value);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+// 校验url只能是e(x)hentai的https地址，并识别出来源类型
+pub fn parse_url(url: &str) -> (Url, Source) {
+    let download_url: Url = Url::parse(url.trim()).expect("Parse url failed");
+    assert!(download_url.scheme() == "https");
+    assert!(
+        download_url.host() == Some(Host::Domain("e-hentai.org"))
+            || download_url.host() == Some(Host::Domain("exhentai.org"))
+    );
+
+    match Source::from_url(&download_url) {
+        Some(s) => (download_url, s),
+        None => {
+            eprintln!(
+                "Error: unsupported url '{}'.\n\
+                 Supported: /g/<id>/<token>/, /tag/<tag>, /uploader/<name>, /favorites.php, /?f_search=<keyword>",
+                download_url
+            );
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// --cover / --thumbnails：保存图集封面和每一页的缩略图，供浏览界面使用
+// 都是缩略图服务器(ehgt.org / s.exhentai.org)上的图片，不消耗图片配额
+
+use crate::handler::{DownloadError, Handler};
+use crate::library;
+use crate::logger;
+use crate::manga::{self, Crop, Manga, Thumbnail};
+use image::ImageFormat;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// 图集目录下封面的文件名
+pub const COVER: &str = "cover.jpg";
+
+/// 封面保存为图集目录下的 cover.jpg，已经存在时跳过
+/// 缩略图服务器上的封面可能是webp/png，转换成jpg保存，浏览界面只需要找固定的文件名
+pub fn save_cover(m: &Manga, path: &str, cookie: &str, force: bool) -> Result<(), DownloadError> {
+    let url = match &m.cover {
+        Some(url) => url,
+        None => {
+            logger::warn(&format!("No cover found for gallery {}", m.number));
+            return Ok(());
+        }
+    };
+    let fname = Path::new(path).join(COVER);
+    if !force && fname.exists() {
+        return Ok(());
+    }
+
+    let bytes = fetch(url, cookie)?;
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        fs::write(&fname, bytes).map_err(DownloadError::Io)?;
+    } else {
+        image::load_from_memory(&bytes)
+            .map_err(|e| DownloadError::Parse(format!("Decode cover {} failed: {}", url, e)))?
+            .to_rgb8()
+            .save_with_format(&fname, ImageFormat::Jpeg)
+            .map_err(|e| DownloadError::Io(std::io::Error::other(e)))?;
+    }
+    logger::info(&format!("Saved cover {}", fname.display()));
+    Ok(())
+}
+
+/// 每一页的缩略图保存到 thumbnails/<id>-<n>.<ext>
+/// sprite 只下载一次，裁剪成单独的图片后保存为jpg；返回保存的数量
+pub fn save_thumbnails(thumbnails: &[Thumbnail], path: &str, cookie: &str, force: bool) -> Result<usize, DownloadError> {
+    let dir = Path::new(path).join("thumbnails");
+    fs::create_dir_all(&dir).map_err(DownloadError::Io)?;
+
+    let mut sprites: HashMap<&str, image::DynamicImage> = HashMap::new();
+    let mut saved = 0;
+    for thumb in thumbnails {
+        let name = manga::page_name(&thumb.link);
+        if !force && library::find_local(&dir, name).is_some() {
+            continue;
+        }
+        let url = match &thumb.url {
+            Some(url) => url.as_str(),
+            None => continue,
+        };
+
+        match thumb.crop {
+            Some(crop) => {
+                if !sprites.contains_key(url) {
+                    let bytes = fetch(url, cookie)?;
+                    let sprite = image::load_from_memory(&bytes)
+                        .map_err(|e| DownloadError::Parse(format!("Decode thumbnail {} failed: {}", url, e)))?;
+                    sprites.insert(url, sprite);
+                }
+                let image = crop_to(&sprites[url], crop);
+                image
+                    .to_rgb8()
+                    .save_with_format(dir.join(format!("{}.jpg", name)), ImageFormat::Jpeg)
+                    .map_err(|e| DownloadError::Io(std::io::Error::other(e)))?;
+            }
+            None => {
+                let bytes = fetch(url, cookie)?;
+                fs::write(dir.join(format!("{}.{}", name, extension(url))), bytes).map_err(DownloadError::Io)?;
+            }
+        }
+        saved += 1;
+    }
+    Ok(saved)
+}
+
+// 裁剪区域超出sprite时(最后一行往往不满)只保留sprite内的部分
+fn crop_to(sprite: &image::DynamicImage, crop: Crop) -> image::DynamicImage {
+    let x = crop.x.min(sprite.width());
+    let y = crop.y.min(sprite.height());
+    let width = crop.width.min(sprite.width() - x);
+    let height = crop.height.min(sprite.height() - y);
+    sprite.crop_imm(x, y, width, height)
+}
+
+fn fetch(url: &str, cookie: &str) -> Result<Vec<u8>, DownloadError> {
+    let host = reqwest::Url::parse(url)
+        .map_err(|e| DownloadError::Parse(format!("{}: {}", url, e)))?
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    let res = Handler::new(&host, cookie).request_image(url)?;
+    res.bytes().map(|b| b.to_vec()).map_err(DownloadError::Request)
+}
+
+// 地址中的扩展名，忽略查询参数
+fn extension(url: &str) -> &str {
+    url.split(['?', '#'])
+        .next()
+        .and_then(|u| u.rsplit('/').next())
+        .and_then(|name| name.rsplit_once('.'))
+        .map_or("jpg", |(_, ext)| ext)
+}
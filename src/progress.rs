@@ -0,0 +1,324 @@
+// 下载进度：所有线程共享同一个计数器，终端中在最后一行显示进度条
+// stdout 不是终端(重定向到文件、cron)时只输出普通的文本行
+// --report - 时stdout留给JSON报告，进度条和日志都输出到stderr
+
+use crate::handler::DownloadError;
+use crate::logger::{self, Level};
+use crate::retry::ErrorClass;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::new(0)));
+
+// 本次下载收到的字节数，单独计数避免每次read都加锁
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// 刷新进度条的线程，finish 时等它退出，避免下一次 start 时有两个线程同时刷新
+static DRAWER: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
+
+// 进度条和普通日志输出到stderr
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// --log-format json 或 -q 时不显示进度条
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+fn bar_enabled() -> bool {
+    let is_tty = match TO_STDERR.load(Ordering::SeqCst) {
+        true => io::stderr().is_terminal(),
+        false => io::stdout().is_terminal(),
+    };
+    is_tty && !DISABLED.load(Ordering::SeqCst)
+}
+
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// 在开始下载前调用，之后的输出都写到stderr
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::SeqCst);
+}
+
+fn out() -> Box<dyn Write> {
+    match TO_STDERR.load(Ordering::SeqCst) {
+        true => Box::new(io::stderr()),
+        false => Box::new(io::stdout()),
+    }
+}
+
+const TICK: Duration = Duration::from_millis(250);
+
+struct State {
+    total: usize,
+    completed: usize,
+    skipped: usize,
+    failed: Vec<Failure>,
+    /// 正在下载的文件数
+    active: usize,
+    started: Instant,
+    /// 进度条是否已经显示在最后一行
+    drawn: bool,
+}
+
+impl State {
+    fn new(total: usize) -> Self {
+        State {
+            total,
+            completed: 0,
+            skipped: 0,
+            failed: vec![],
+            active: 0,
+            started: Instant::now(),
+            drawn: false,
+        }
+    }
+
+    fn finished(&self) -> usize {
+        self.completed + self.skipped + self.failed.len()
+    }
+
+    fn line(&self) -> String {
+        let bytes = BYTES.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+
+        // 剩余时间按照本次已下载的页面的平均时间估算，跳过的页面不算
+        let remaining = self.total.saturating_sub(self.finished());
+        let eta = if self.completed > 0 && remaining > 0 {
+            format_eta(Duration::from_secs_f64(elapsed / self.completed as f64 * remaining as f64))
+        } else {
+            "--".to_string()
+        };
+
+        format!(
+            "[{}/{}] ok {} skipped {} failed {} active {} | {} | {}/s | ETA {}",
+            self.finished(),
+            self.total,
+            self.completed,
+            self.skipped,
+            self.failed.len(),
+            self.active,
+            format_bytes(bytes),
+            format_bytes(speed as u64),
+            eta
+        )
+    }
+
+    fn draw(&mut self, out: &mut impl Write) {
+        let _ = write!(out, "\r\x1b[2K{}", self.line());
+        let _ = out.flush();
+        self.drawn = true;
+    }
+
+    fn clear(&mut self, out: &mut impl Write) {
+        if self.drawn {
+            let _ = write!(out, "\r\x1b[2K");
+            self.drawn = false;
+        }
+    }
+}
+
+/// 开始一组下载，终端中启动刷新进度条的线程
+pub fn start(total: usize) {
+    *STATE.lock().unwrap() = State::new(total);
+    BYTES.store(0, Ordering::Relaxed);
+
+    let mut drawer = DRAWER.lock().unwrap();
+    if bar_enabled() && drawer.is_none() {
+        RUNNING.store(true, Ordering::SeqCst);
+        *drawer = Some(thread::spawn(|| {
+            while RUNNING.load(Ordering::SeqCst) {
+                STATE.lock().unwrap().draw(&mut out());
+                thread::sleep(TICK);
+            }
+        }));
+    }
+}
+
+/// 下载失败的页面和原因
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub file: String,
+    pub class: ErrorClass,
+    /// 重新运行是否可能成功
+    pub retryable: bool,
+    pub error: String,
+}
+
+/// 一组下载结束时的统计
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: Vec<Failure>,
+    pub bytes: u64,
+}
+
+impl Stats {
+    /// 同一个图集再次下载(例如配额恢复后)的结果累加进来，失败列表以最后一次为准
+    pub fn merge(&mut self, next: Stats) {
+        self.downloaded += next.downloaded;
+        self.skipped += next.skipped;
+        self.bytes += next.bytes;
+        self.failed = next.failed;
+    }
+}
+
+/// 结束这组下载：停止刷新，输出并返回汇总
+pub fn finish() -> Stats {
+    RUNNING.store(false, Ordering::SeqCst);
+    if let Some(drawer) = DRAWER.lock().unwrap().take() {
+        let _ = drawer.join();
+    }
+    let (line, stats) = {
+        let mut st = STATE.lock().unwrap();
+        st.clear(&mut out());
+        let stats = Stats {
+            downloaded: st.completed,
+            skipped: st.skipped,
+            failed: st.failed.clone(),
+            bytes: BYTES.load(Ordering::Relaxed),
+        };
+        (format!("{} in {}", st.line(), format_eta(st.started.elapsed())), stats)
+    };
+    logger::info(&line);
+    stats
+}
+
+/// 输出一行，不会和进度条混在一起；其他模块应该通过 logger 输出
+pub fn println(msg: &str) {
+    let mut st = STATE.lock().unwrap();
+    let mut out = out();
+    st.clear(&mut out);
+    let _ = writeln!(out, "{}", msg);
+    if RUNNING.load(Ordering::SeqCst) {
+        st.draw(&mut out);
+    }
+}
+
+pub fn eprintln(msg: &str) {
+    let mut st = STATE.lock().unwrap();
+    let mut out = out();
+    st.clear(&mut out);
+    let _ = out.flush();
+    let _ = writeln!(io::stderr(), "{}", msg);
+    if RUNNING.load(Ordering::SeqCst) {
+        st.draw(&mut out);
+    }
+}
+
+/// 只在没有进度条时打印的细节信息，终端中由进度条代替
+pub fn detail(msg: &str) {
+    if !bar_enabled() {
+        println(msg);
+    }
+}
+
+pub fn started(name: &str) {
+    STATE.lock().unwrap().active += 1;
+    logger::event(
+        Level::Info,
+        "downloading",
+        &format!("Downloading: {}", name),
+        json!({ "file": name }),
+    );
+}
+
+/// 一个文件下载并校验完成，显示这个文件的大小和速度
+pub fn completed(name: &str, size: u64, elapsed: Duration) {
+    let line = {
+        let mut st = STATE.lock().unwrap();
+        st.active = st.active.saturating_sub(1);
+        st.completed += 1;
+        let speed = size as f64 / elapsed.as_secs_f64().max(0.001);
+        format!(
+            "[{}/{}] Verified: {} ({}, {}/s)",
+            st.finished(),
+            st.total,
+            name,
+            format_bytes(size),
+            format_bytes(speed as u64)
+        )
+    };
+    logger::event(
+        Level::Info,
+        "verified",
+        &line,
+        json!({ "file": name, "bytes": size, "elapsed_ms": elapsed.as_millis() as u64 }),
+    );
+}
+
+/// 下载失败(还会重试)，只减少正在下载的数量
+pub fn aborted() {
+    let mut st = STATE.lock().unwrap();
+    st.active = st.active.saturating_sub(1);
+}
+
+/// 本地已有的文件
+pub fn skipped(name: &str) {
+    let line = {
+        let mut st = STATE.lock().unwrap();
+        st.skipped += 1;
+        format!("[{}/{}] Skipped (already exists): {}", st.finished(), st.total, name)
+    };
+    logger::event(Level::Info, "skipped", &line, json!({ "file": name }));
+}
+
+/// 重试次数用完、不可重试或者无法解析的页面
+pub fn failed(file: &str, e: &DownloadError) {
+    STATE.lock().unwrap().failed.push(Failure {
+        file: file.to_string(),
+        class: e.class(),
+        retryable: e.is_retryable(),
+        error: e.to_string(),
+    });
+}
+
+/// 使用 -r 时失败的页面会重新下载
+pub fn retrying(files: &[String]) {
+    let mut st = STATE.lock().unwrap();
+    st.failed.retain(|f| !files.contains(&f.file));
+}
+
+/// 统计收到的字节数
+pub struct Counted<R>(pub R);
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        BYTES.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_eta(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,90 @@
+// --on-quota：图片配额(Image Limits)用完之后的处理方式
+// 配额在 https://e-hentai.org/home.php 中显示，需要登录cookie
+
+use crate::handler::{DownloadError, Handler};
+use crate::interrupt;
+use crate::logger;
+use std::time::Duration;
+
+const LIMITS_URL: &str = "https://e-hentai.org/home.php";
+
+/// 配额用完时的策略
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaStrategy {
+    /// 保存进度并停止，不再下载后面的图集
+    Stop,
+    /// 定期检查配额，恢复后继续下载剩下的页面
+    Wait,
+    /// 改为下载图集的压缩包(archiver.php，消耗GP)
+    Archive,
+    /// 改为下载图集的种子文件
+    Torrent,
+}
+
+impl QuotaStrategy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "stop" => Some(QuotaStrategy::Stop),
+            "wait" => Some(QuotaStrategy::Wait),
+            "archive" => Some(QuotaStrategy::Archive),
+            "torrent" => Some(QuotaStrategy::Torrent),
+            _ => None,
+        }
+    }
+}
+
+/// home.php 中的 "You are currently at <strong>X</strong> towards your account limit of <strong>Y</strong>"
+/// 返回 (已用, 上限)，没有登录或者页面格式不同时为None
+pub fn limits(cookie: &str) -> Result<Option<(u32, u32)>, DownloadError> {
+    let body = Handler::new("e-hentai.org", cookie).request("Get image limits", LIMITS_URL)?;
+    let numbers: Vec<u32> = match body.split_once("You are currently at") {
+        Some((_, rest)) => rest
+            .split("<strong>")
+            .skip(1)
+            .take(2)
+            .filter_map(|s| s.split("</strong>").next()?.replace(',', "").trim().parse().ok())
+            .collect(),
+        None => return Ok(None),
+    };
+    match numbers.as_slice() {
+        [used, limit] => Ok(Some((*used, *limit))),
+        _ => Ok(None),
+    }
+}
+
+/// 每隔 poll 检查一次配额，直到已用的低于上限
+/// 无法读取配额时等待一个间隔后直接返回，由下一次下载来判断是否恢复
+pub fn wait_for_reset(cookie: &str, poll: Duration) -> Result<(), DownloadError> {
+    loop {
+        let status = match limits(cookie) {
+            Ok(status) => status,
+            Err(e @ (DownloadError::Banned(_) | DownloadError::Interrupted)) => return Err(e),
+            Err(e) => {
+                logger::warn(&format!("Check image limits failed: {}", e));
+                None
+            }
+        };
+        match status {
+            Some((used, limit)) if used < limit => {
+                logger::info(&format!("Image limits reset ({}/{}), continue downloading", used, limit));
+                return Ok(());
+            }
+            Some((used, limit)) => logger::info(&format!(
+                "Image limits {}/{}, check again in {}s",
+                used,
+                limit,
+                poll.as_secs()
+            )),
+            None => logger::info(&format!(
+                "Image limits unknown (login cookie required), retry in {}s",
+                poll.as_secs()
+            )),
+        }
+
+        interrupt::sleep(poll);
+        interrupt::check()?;
+        if status.is_none() {
+            return Ok(());
+        }
+    }
+}
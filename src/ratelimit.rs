@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 请求的类型，HTML页面和图片分别限速
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// 图集页、缩略图页、图片页等HTML页面，直接影响是否被封IP
+    Page,
+    /// 图片文件(H@H节点)
+    Image,
+}
+
+/// 令牌桶：以rate个/秒的速度生成令牌，最多积攒burst个
+/// 遇到429/503时速度减半，之后每次成功的请求慢慢恢复
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+    /// 当前速度 = rate * factor，factor 在 (0, 1] 之间
+    factor: f64,
+}
+
+const MIN_FACTOR: f64 = 1.0 / 32.0;
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last: Instant::now(),
+                factor: 1.0,
+            }),
+        }
+    }
+
+    /// 取一个令牌，没有令牌时阻塞等待
+    pub fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut st = self.state.lock().unwrap();
+                let now = Instant::now();
+                let rate = self.rate * st.factor;
+                st.tokens = (st.tokens + now.duration_since(st.last).as_secs_f64() * rate).min(self.burst);
+                st.last = now;
+
+                if st.tokens >= 1.0 {
+                    st.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - st.tokens) / rate)
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    /// 服务器返回429/503：速度减半并清空已积攒的令牌
+    pub fn penalize(&self) {
+        let mut st = self.state.lock().unwrap();
+        st.factor = (st.factor / 2.0).max(MIN_FACTOR);
+        st.tokens = 0.0;
+    }
+
+    /// 请求成功：逐渐恢复到设定的速度
+    pub fn reward(&self) {
+        let mut st = self.state.lock().unwrap();
+        if st.factor < 1.0 {
+            st.factor = (st.factor * 1.05).min(1.0);
+        }
+    }
+
+    pub fn current_rate(&self) -> f64 {
+        self.rate * self.state.lock().unwrap().factor
+    }
+}
+
+/// 按照请求类型管理令牌桶，所有线程共享
+/// HTML页面按主机分别限速；图片来自很多不同的H@H节点，--image-rate 是所有节点加起来的速度，
+/// 只有 --host-rate 单独指定了速度的主机使用自己的令牌桶
+pub struct RateLimiter {
+    page_rate: f64,
+    image_rate: f64,
+    /// 单独指定速度的主机
+    host_rates: HashMap<String, f64>,
+    buckets: Mutex<HashMap<(RequestKind, String), Arc<TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(page_rate: f64, image_rate: f64, host_rates: HashMap<String, f64>) -> Self {
+        RateLimiter {
+            page_rate,
+            image_rate,
+            host_rates,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn bucket(&self, kind: RequestKind, host: &str) -> Arc<TokenBucket> {
+        let override_rate = self.host_rates.get(host).copied();
+        // 没有单独指定速度的图片主机共用一个令牌桶
+        let key = match (kind, override_rate) {
+            (RequestKind::Image, None) => String::new(),
+            _ => host.to_string(),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry((kind, key))
+            .or_insert_with(|| {
+                let rate = override_rate.unwrap_or(match kind {
+                    RequestKind::Page => self.page_rate,
+                    RequestKind::Image => self.image_rate,
+                });
+                // 允许短暂的突发，但最多1秒的量
+                Arc::new(TokenBucket::new(rate, rate.max(1.0)))
+            })
+            .clone()
+    }
+
+    /// 丢弃所有令牌桶(包括减速的状态)
+    #[cfg(test)]
+    pub fn reset(&self) {
+        self.buckets.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,194 @@
+// 运行报告：每个图集的下载结果汇总成JSON，写到 --report 指定的文件(- 表示stdout)
+// 退出码也由这里的结果决定，方便cron和CI根据不同的情况处理
+
+use crate::progress::{Failure, Stats};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static REPORT: Lazy<Mutex<Vec<GalleryReport>>> = Lazy::new(|| Mutex::new(vec![]));
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+static QUOTA_EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+/// 退出码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    /// 其他错误(参数错误、网络错误等)
+    Error = 1,
+    /// 部分页面下载失败
+    Partial = 3,
+    /// 需要登录(exhentai.org没有提供cookie)
+    AuthRequired = 4,
+    /// IP被临时封禁
+    Banned = 5,
+    /// 图片配额用完
+    QuotaExceeded = 6,
+    /// 图集不存在或者没有可下载的内容
+    Unavailable = 7,
+    /// 按下了Ctrl-C
+    Interrupted = 130,
+}
+
+/// 单个图集的结果，对应 main 中的 Outcome
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Complete,
+    /// 配额用完，改为下载了包含所有页面的压缩包
+    Archived,
+    Partial,
+    Unavailable,
+    AuthRequired,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GalleryReport {
+    pub id: Option<u32>,
+    pub title: Option<String>,
+    pub url: String,
+    pub status: Status,
+    pub total_pages: usize,
+    pub downloaded: usize,
+    /// 本地已有的页面和从其他图集复用的页面
+    pub skipped: usize,
+    pub failed: Vec<Failure>,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+impl GalleryReport {
+    pub fn new(url: &str, status: Status) -> Self {
+        GalleryReport {
+            id: None,
+            title: None,
+            url: url.to_string(),
+            status,
+            total_pages: 0,
+            downloaded: 0,
+            skipped: 0,
+            failed: vec![],
+            bytes: 0,
+            duration_ms: 0,
+        }
+    }
+
+    /// 合并一组下载的统计
+    pub fn add_stats(&mut self, stats: Stats) {
+        self.downloaded += stats.downloaded;
+        self.skipped += stats.skipped;
+        self.failed.extend(stats.failed);
+        self.bytes += stats.bytes;
+    }
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    exit_code: i32,
+    duration_ms: u64,
+    galleries: &'a [GalleryReport],
+}
+
+/// 在程序开始时调用，记录开始时间
+pub fn start() {
+    Lazy::force(&STARTED);
+}
+
+pub fn add(gallery: GalleryReport) {
+    REPORT.lock().unwrap().push(gallery);
+}
+
+pub fn mark_quota_exceeded() {
+    QUOTA_EXCEEDED.store(true, Ordering::SeqCst);
+}
+
+/// --on-quota wait 等到配额恢复之后清除
+pub fn clear_quota_exceeded() {
+    QUOTA_EXCEEDED.store(false, Ordering::SeqCst);
+}
+
+pub fn quota_exceeded() -> bool {
+    QUOTA_EXCEEDED.load(Ordering::SeqCst)
+}
+
+/// 根据所有图集的结果决定退出码，更严重的情况优先
+/// error 为 run 返回的错误对应的退出码
+pub fn exit_code(error: Option<ExitCode>) -> ExitCode {
+    if let Some(code @ (ExitCode::Banned | ExitCode::Interrupted)) = error {
+        return code;
+    }
+    let galleries = REPORT.lock().unwrap();
+    let has = |status| galleries.iter().any(|g| g.status == status);
+
+    // 配额用完后所有图集都通过压缩包拿到了，不算失败
+    let unfinished = galleries.is_empty() || galleries.iter().any(|g| !matches!(g.status, Status::Complete | Status::Archived));
+    if quota_exceeded() && unfinished {
+        return ExitCode::QuotaExceeded;
+    }
+    if let Some(code) = error {
+        return code;
+    }
+
+    if has(Status::AuthRequired) {
+        ExitCode::AuthRequired
+    } else if has(Status::Unavailable) {
+        ExitCode::Unavailable
+    } else if has(Status::Partial) {
+        ExitCode::Partial
+    } else {
+        ExitCode::Success
+    }
+}
+
+/// 写出报告，path 为 - 时输出到stdout
+pub fn write(path: &Path, code: ExitCode) -> io::Result<()> {
+    let galleries = REPORT.lock().unwrap();
+    let summary = Summary {
+        exit_code: code as i32,
+        duration_ms: STARTED.elapsed().as_millis() as u64,
+        galleries: &galleries,
+    };
+    let json = serde_json::to_string_pretty(&summary).map_err(io::Error::other)?;
+
+    if path == Path::new("-") {
+        writeln!(io::stdout(), "{}", json)
+    } else {
+        fs::write(path, json + "\n")
+    }
+}
+
+/// 清空已记录的结果和配额状态
+#[cfg(test)]
+pub fn reset() {
+    REPORT.lock().unwrap().clear();
+    QUOTA_EXCEEDED.store(false, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler;
+
+    #[test]
+    fn quota_exit_code() {
+        let _globals = handler::lock_globals();
+        mark_quota_exceeded();
+        add(GalleryReport::new("https://e-hentai.org/g/1/a/", Status::Complete));
+        add(GalleryReport::new("https://e-hentai.org/g/2/b/", Status::Archived));
+        // 配额用完后剩下的图集都通过压缩包下载了
+        assert_eq!(exit_code(None), ExitCode::Success);
+
+        add(GalleryReport::new("https://e-hentai.org/g/3/c/", Status::Partial));
+        assert_eq!(exit_code(None), ExitCode::QuotaExceeded);
+
+        clear_quota_exceeded();
+        assert_eq!(exit_code(None), ExitCode::Partial);
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 错误分类，不同类型的错误使用不同的重试次数
+/// 分类只说明错误的原因，是否可以重试由 DownloadError::is_retryable 决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorClass {
+    /// 建立连接失败
+    Connect,
+    /// 超时
+    Timeout,
+    /// 5xx 服务器错误
+    Server,
+    /// 429/503 请求太快
+    Throttled,
+    /// 下载过程中的IO错误
+    Io,
+    /// 文件校验失败
+    Verification,
+    /// 4xx 客户端错误(404等)
+    Client,
+    /// 页面解析失败
+    Parse,
+    /// 图片配额用完
+    Quota,
+    /// IP被封
+    Banned,
+    /// 按下了Ctrl-C
+    Interrupted,
+    /// 其他请求错误(重定向、解码失败等)
+    Other,
+}
+
+/// 重试策略：指数退避 base * 2^attempt，最多cap，再加上随机抖动
+/// 服务器返回 Retry-After 时优先使用它(最多 retry_after_cap 秒)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// 每个文件最多尝试的次数
+    pub max_attempts: u32,
+    /// 第一次重试前的等待时间(毫秒)
+    pub base_ms: u64,
+    /// 最长的等待时间(毫秒)
+    pub cap_ms: u64,
+    /// 随机抖动的上限(毫秒)
+    pub jitter_ms: u64,
+    /// 各类错误的重试次数，不超过 max_attempts
+    pub connect_attempts: u32,
+    pub timeout_attempts: u32,
+    pub server_attempts: u32,
+    pub throttled_attempts: u32,
+    /// Retry-After 最多等待的秒数
+    pub retry_after_cap: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_ms: 500,
+            cap_ms: 10_000,
+            jitter_ms: 300,
+            connect_attempts: 5,
+            timeout_attempts: 3,
+            server_attempts: 5,
+            throttled_attempts: 5,
+            retry_after_cap: 300,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 某类错误最多尝试的次数
+    pub fn budget(&self, class: ErrorClass) -> u32 {
+        let n = match class {
+            ErrorClass::Connect => self.connect_attempts,
+            ErrorClass::Timeout => self.timeout_attempts,
+            ErrorClass::Server => self.server_attempts,
+            ErrorClass::Throttled => self.throttled_attempts,
+            ErrorClass::Io | ErrorClass::Verification => self.max_attempts,
+            ErrorClass::Client
+            | ErrorClass::Parse
+            | ErrorClass::Quota
+            | ErrorClass::Banned
+            | ErrorClass::Interrupted
+            | ErrorClass::Other => 1,
+        };
+        n.min(self.max_attempts)
+    }
+
+    /// 第attempt次失败后的等待时间
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(d) = retry_after {
+            return d.min(Duration::from_secs(self.retry_after_cap));
+        }
+
+        // 2^attempt，但最多放大到 2^5
+        let exp = self.base_ms.saturating_mul(2_u64.pow(attempt.min(5)));
+        let delay = (exp + self.jitter()).min(self.cap_ms);
+
+        Duration::from_millis(delay)
+    }
+
+    fn jitter(&self) -> u64 {
+        if self.jitter_ms == 0 {
+            return 0;
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+
+        nanos as u64 % self.jitter_ms
+    }
+
+    /// 命令行中的 <class>=<n>，class 为 connect/timeout/server/throttled
+    pub fn set_budget(&mut self, class: &str, n: u32) -> Result<(), String> {
+        match class {
+            "connect" => self.connect_attempts = n,
+            "timeout" => self.timeout_attempts = n,
+            "server" => self.server_attempts = n,
+            "throttled" => self.throttled_attempts = n,
+            _ => return Err(format!("unknown error class '{}'", class)),
+        }
+        Ok(())
+    }
+}
+
+/// Retry-After 头：秒数或者HTTP日期(例如 Wed, 21 Oct 2015 07:28:00 GMT)
+/// 日期已经过去时返回0
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        // 已经过去的日期
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+
+        let later = SystemTime::now() + Duration::from_secs(90);
+        let wait = parse_retry_after(&httpdate::fmt_http_date(later)).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+    }
+}
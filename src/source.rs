@@ -0,0 +1,114 @@
+use crate::handler::Handler;
+use crate::logger;
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::{Attr, Name};
+
+/// 命令行 `--url` 所指向的下载来源
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// 单个图集：/g/<id>/<token>/
+    Gallery,
+    /// 标签列表：/tag/<tag>
+    Tag,
+    /// 上传者列表：/uploader/<name>
+    Uploader,
+    /// 收藏夹：/favorites.php
+    Favorites,
+    /// 搜索结果：/?f_search=...
+    Search,
+}
+
+impl Source {
+    /// 根据url的路径判断来源类型，无法识别时返回None
+    pub fn from_url(url: &Url) -> Option<Source> {
+        let segs: Vec<&str> = url
+            .path_segments()
+            .map(|c| c.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+
+        match segs.as_slice() {
+            ["g", id, _token] if id.parse::<u32>().is_ok() => Some(Source::Gallery),
+            ["tag", _] => Some(Source::Tag),
+            ["uploader", _] => Some(Source::Uploader),
+            ["favorites.php"] => Some(Source::Favorites),
+            [] if url.query_pairs().any(|(k, _)| k == "f_search") => Some(Source::Search),
+            _ => None,
+        }
+    }
+
+    pub fn is_listing(&self) -> bool {
+        *self != Source::Gallery
+    }
+}
+
+/// 从图集url(/g/<id>/<token>/)中取出图集id
+pub fn gallery_id(url: &Url) -> Option<u32> {
+    let mut segs = url.path_segments()?.filter(|s| !s.is_empty());
+    match (segs.next(), segs.next()) {
+        (Some("g"), Some(id)) => id.parse::<u32>().ok(),
+        _ => None,
+    }
+}
+
+/// 展开列表页(tag/uploader/favorites/search)，返回其中的图集地址
+/// 会沿着"下一页"链接翻页，直到达到limit或者没有更多结果
+/// 其他主机的图集链接会被忽略
+pub fn expand_listing(h: &Handler, url: &Url, limit: Option<usize>) -> Vec<Url> {
+    let mut galleries: Vec<Url> = vec![];
+    let mut next = Some(url.clone());
+
+    while let Some(page) = next.take() {
+        if limit.is_some_and(|n| galleries.len() >= n) {
+            break;
+        }
+
+        let body = match h.request("Get listing page", page.as_str()) {
+            Ok(b) => b,
+            Err(e) => {
+                logger::error(&format!("Get listing page failed: {} ({})", page, e));
+                break;
+            }
+        };
+
+        let doc = Document::from(body.as_str());
+        let mut found = 0;
+        for href in doc.find(Name("a")).filter_map(|n| n.attr("href")) {
+            let gallery = match page.join(href) {
+                Ok(u) => u,
+                Err(_) => continue,
+            };
+            // 只保留和列表页同一主机的图集，之后会用列表页主机的 Handler 下载
+            if Source::from_url(&gallery) != Some(Source::Gallery) || gallery.host() != page.host() {
+                continue;
+            }
+            // 同一个图集可能以不同的形式出现(/g/1/abc 和 /g/1/abc/?p=0)，按图集id去重
+            let id = gallery_id(&gallery);
+            if !galleries.iter().any(|g| gallery_id(g) == id) {
+                galleries.push(gallery);
+                found += 1;
+            }
+        }
+
+        // 当前页没有新的图集，说明已经到底了
+        if found == 0 {
+            break;
+        }
+
+        next = next_page(&doc, &page);
+    }
+
+    if let Some(n) = limit {
+        galleries.truncate(n);
+    }
+    galleries
+}
+
+// 新版列表页使用 id="unext"/"dnext" 的链接作为下一页(next=<gid>游标)
+fn next_page(doc: &Document, page: &Url) -> Option<Url> {
+    doc.find(Attr("id", "unext"))
+        .chain(doc.find(Attr("id", "dnext")))
+        .filter_map(|n| n.attr("href"))
+        .find_map(|href| page.join(href).ok())
+        .filter(|u| u != page)
+}
@@ -0,0 +1,176 @@
+// 低速检测：卡住的H@H节点可能一直保持连接但几乎不发送数据，只靠总超时要等很久
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 包装下载的Reader，每个时间窗口内收到的数据少于 min_speed * window 时返回 TimedOut
+pub struct StallReader<R> {
+    inner: R,
+    /// 最低速度(字节/秒)，0表示不检测
+    min_speed: u64,
+    window: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl<R: Read> StallReader<R> {
+    pub fn new(inner: R, min_speed: u64, window: Duration) -> Self {
+        StallReader {
+            inner,
+            min_speed,
+            window,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for StallReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.min_speed == 0 || self.window.is_zero() {
+            return Ok(n);
+        }
+
+        self.window_bytes += n as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= self.window {
+            let speed = self.window_bytes as f64 / elapsed.as_secs_f64();
+            if speed < self.min_speed as f64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "download stalled ({:.1} KB/s for {}s, minimum {} KB/s)",
+                        speed / 1024.0,
+                        elapsed.as_secs(),
+                        self.min_speed / 1024
+                    ),
+                ));
+            }
+            // 开始新的时间窗口
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        Ok(n)
+    }
+}
+
+/// 完全收不到数据时 read 会一直阻塞，StallReader 没有机会检查速度
+/// 在单独的线程中读取，超过 timeout 没有收到任何数据时返回 TimedOut
+/// 被放弃的读取线程在连接的总超时(--timeout)之后结束
+pub struct IdleReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    timeout: Duration,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl IdleReader {
+    /// timeout 为0表示不检测
+    pub fn new<R: Read + Send + 'static>(mut inner: R, timeout: Duration) -> Self {
+        // 最多缓存几块数据，写文件慢的时候读取线程也会停下来
+        let (tx, rx) = mpsc::sync_channel(4);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let result = inner.read(&mut chunk).map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            let last = !matches!(&result, Ok(c) if !c.is_empty());
+            if tx.send(result).is_err() || last {
+                break;
+            }
+        });
+        IdleReader {
+            rx,
+            timeout,
+            chunk: vec![],
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for IdleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let received = match self.timeout.is_zero() {
+                true => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                false => self.rx.recv_timeout(self.timeout),
+            };
+            match received {
+                Ok(Ok(chunk)) => {
+                    self.done = chunk.is_empty();
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => {
+                    self.done = true;
+                    return Err(e);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("download stalled (no data for {}s)", self.timeout.as_secs()),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("download reader thread exited"))
+                }
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 先返回一段数据，之后一直不返回
+    struct Hang(bool);
+
+    impl Read for Hang {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 {
+                thread::sleep(Duration::from_secs(5));
+                return Ok(0);
+            }
+            self.0 = true;
+            buf[..3].copy_from_slice(b"abc");
+            Ok(3)
+        }
+    }
+
+    #[test]
+    fn idle_reader_passes_data_through() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut reader = IdleReader::new(io::Cursor::new(data.clone()), Duration::from_secs(5));
+        let mut out = vec![];
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn idle_reader_times_out_without_data() {
+        let mut reader = IdleReader::new(Hang(false), Duration::from_millis(100));
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        let started = Instant::now();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
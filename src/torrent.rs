@@ -0,0 +1,121 @@
+// --torrent：下载图集的种子文件(gallerytorrents.php)代替逐张下载图片，完全不消耗图片配额
+// 可以用 --torrent-client 把下载的种子交给外部的BT客户端
+
+use crate::handler::{DownloadError, Handler};
+use crate::manga::Manga;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::Name;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+#[derive(Debug, Clone)]
+pub struct Torrent {
+    pub name: String,
+    pub url: String,
+    /// 发布时间，例如 "2015-09-03 03:28"
+    pub posted: String,
+    /// 例如 "89.63 MiB"
+    pub size: String,
+    pub seeds: u32,
+    pub peers: u32,
+    pub downloads: u32,
+}
+
+/// 图集的种子列表，没有种子时为空
+pub fn list(h: &Handler, m: &Manga) -> Result<Vec<Torrent>, DownloadError> {
+    let url = m
+        .url
+        .join(&format!("/gallerytorrents.php?gid={}&t={}", m.number, m.token))
+        .map_err(|e| DownloadError::Parse(e.to_string()))?;
+    let body = h.request("Get torrents", url.as_str())?;
+    Ok(Document::from(body.as_str())
+        .find(Name("form"))
+        .filter_map(|form| parse_torrent(&form))
+        .collect())
+}
+
+// 每个种子是一个 <form>，表格中是 "Posted: ..."、"Size: ..." 这样的单元格，最后一行是种子链接
+fn parse_torrent(form: &Node) -> Option<Torrent> {
+    let link = form.find(Name("a")).find_map(|a| torrent_url(&a).map(|url| (a.text(), url)))?;
+
+    let mut torrent = Torrent {
+        name: link.0.trim().to_string(),
+        url: link.1,
+        posted: String::new(),
+        size: String::new(),
+        seeds: 0,
+        peers: 0,
+        downloads: 0,
+    };
+    for td in form.find(Name("td")) {
+        let text = td.text();
+        let (label, value) = match text.split_once(':') {
+            Some((label, value)) => (label.trim(), value.trim()),
+            None => continue,
+        };
+        match label {
+            "Posted" => torrent.posted = value.to_string(),
+            "Size" => torrent.size = value.to_string(),
+            "Seeds" => torrent.seeds = value.parse().unwrap_or(0),
+            "Peers" => torrent.peers = value.parse().unwrap_or(0),
+            "Downloads" => torrent.downloads = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    Some(torrent)
+}
+
+// 种子地址在 href 中，有些页面 href 为 "#"，地址在 onclick 的 document.location='...' 中
+fn torrent_url(a: &Node) -> Option<String> {
+    if let Some(href) = a.attr("href").filter(|href| href.contains(".torrent")) {
+        return Some(href.to_string());
+    }
+    let onclick = a.attr("onclick")?;
+    let start = onclick.find("document.location='")? + "document.location='".len();
+    let end = start + onclick[start..].find('\'')?;
+    Some(onclick[start..end].to_string()).filter(|url| url.contains(".torrent"))
+}
+
+/// 做种最多的种子，相同时选择最新发布的
+pub fn best(torrents: &[Torrent]) -> Option<&Torrent> {
+    torrents
+        .iter()
+        .max_by(|a, b| a.seeds.cmp(&b.seeds).then_with(|| a.posted.cmp(&b.posted)))
+}
+
+/// 下载种子文件到 dir/<gid>.torrent，返回文件路径
+pub fn download(torrent: &Torrent, m: &Manga, dir: &str, cookie: &str) -> Result<PathBuf, DownloadError> {
+    let host = reqwest::Url::parse(&torrent.url)
+        .map_err(|e| DownloadError::Parse(format!("{}: {}", torrent.url, e)))?
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    // 种子不是图片，但同样需要识别封禁提示和错误状态码
+    let res = Handler::new(&host, cookie).request_image(&torrent.url)?;
+    let bytes = res.bytes().map_err(DownloadError::Request)?;
+    // bencode 的字典以 'd' 开头，其他内容多半是错误页面
+    if !bytes.starts_with(b"d") {
+        return Err(DownloadError::Verification(format!("Not a torrent file: {}", torrent.url)));
+    }
+
+    let fname = Path::new(dir).join(format!("{}.torrent", m.number));
+    fs::write(&fname, &bytes).map_err(DownloadError::Io)?;
+    Ok(fname)
+}
+
+/// 用 --torrent-client 指定的命令打开种子，命令中的 {} 替换为种子路径，没有 {} 时追加在最后
+pub fn open_with(command: &str, path: &Path) -> std::io::Result<ExitStatus> {
+    let path = path.display().to_string();
+    let mut args: Vec<String> = command.split_whitespace().map(String::from).collect();
+    if args.iter().any(|arg| arg.contains("{}")) {
+        args = args.iter().map(|arg| arg.replace("{}", &path)).collect();
+    } else {
+        args.push(path);
+    }
+    if args.len() < 2 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty torrent client command"));
+    }
+    Command::new(&args[0]).args(&args[1..]).status()
+}
@@ -0,0 +1,192 @@
+// 图片结构校验：magic bytes 只能说明文件开头正确，传输到一半被截断的图片需要检查文件结尾和内部结构
+
+/// 按照文件格式检查图片结构是否完整，返回错误原因
+pub fn check_structure(data: &[u8]) -> Result<(), String> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        check_jpeg(data)
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        check_png(data)
+    } else if data.starts_with(b"GIF8") {
+        check_gif(data)
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        check_webp(data)
+    } else {
+        Err("unknown image format".to_string())
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+// JPEG 以 EOI(FF D9) 结尾，部分编码器会在后面补0
+fn check_jpeg(data: &[u8]) -> Result<(), String> {
+    let end = trim_padding(data);
+    if end.ends_with(&[0xFF, 0xD9]) {
+        Ok(())
+    } else {
+        Err("JPEG is missing the EOI marker (truncated)".to_string())
+    }
+}
+
+// PNG 由若干 chunk 组成：长度(4) + 类型(4) + 数据 + CRC(4)，最后一个chunk必须是IEND
+fn check_png(data: &[u8]) -> Result<(), String> {
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        if pos + 8 > data.len() {
+            return Err("PNG is missing the IEND chunk (truncated)".to_string());
+        }
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        // 长度来自文件内容，32位平台上 pos + 8 + len 可能溢出
+        let end = match (pos + 8).checked_add(len) {
+            Some(end) if end <= data.len().saturating_sub(4) => end,
+            _ => {
+                return Err(format!(
+                    "PNG chunk {} is truncated",
+                    String::from_utf8_lossy(kind)
+                ))
+            }
+        };
+
+        let expected = u32::from_be_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+        if crc32(&data[pos + 4..end]) != expected {
+            return Err(format!(
+                "PNG chunk {} has a bad CRC",
+                String::from_utf8_lossy(kind)
+            ));
+        }
+
+        if kind == b"IEND" {
+            return Ok(());
+        }
+        pos = end + 4;
+    }
+}
+
+// GIF 以 trailer(0x3B) 结尾
+fn check_gif(data: &[u8]) -> Result<(), String> {
+    if trim_padding(data).ends_with(&[0x3B]) {
+        Ok(())
+    } else {
+        Err("GIF is missing the trailer (truncated)".to_string())
+    }
+}
+
+// WebP 的 RIFF 头中记录了文件长度(不含前8个字节)
+fn check_webp(data: &[u8]) -> Result<(), String> {
+    let expected = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize + 8;
+    if data.len() == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "WebP RIFF length mismatch (header {} bytes, file {} bytes)",
+            expected,
+            data.len()
+        ))
+    }
+}
+
+fn trim_padding(data: &[u8]) -> &[u8] {
+    let end = data.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    &data[..end]
+}
+
+// CRC-32 (IEEE 802.3)，PNG chunk 使用的校验算法
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按照PNG的格式拼出一个chunk
+    fn chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = (body.len() as u32).to_be_bytes().to_vec();
+        data.extend(kind);
+        data.extend(body);
+        let mut crc_input = kind.to_vec();
+        crc_input.extend(body);
+        data.extend(crc32(&crc_input).to_be_bytes());
+        data
+    }
+
+    fn png() -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0]));
+        data.extend(chunk(b"IDAT", &[0x78, 0x9C, 0x63, 0x00, 0x00]));
+        data.extend(chunk(b"IEND", &[]));
+        data
+    }
+
+    fn webp() -> Vec<u8> {
+        let mut data = b"RIFF".to_vec();
+        data.extend(16u32.to_le_bytes());
+        data.extend(b"WEBPVP8 ");
+        data.extend([0u8; 8]);
+        data
+    }
+
+    #[test]
+    fn jpeg() {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+        data.extend([0xFF, 0xD9, 0x00, 0x00]);
+        assert!(check_structure(&data).is_ok());
+
+        let truncated = &data[..6];
+        assert!(check_structure(truncated).unwrap_err().contains("EOI"));
+    }
+
+    #[test]
+    fn png_crc() {
+        assert!(check_structure(&png()).is_ok());
+
+        let mut bad = png();
+        let idat = PNG_SIGNATURE.len() + 25 + 8;
+        bad[idat] ^= 0xFF;
+        assert!(check_structure(&bad).unwrap_err().contains("bad CRC"));
+    }
+
+    #[test]
+    fn png_truncated() {
+        let data = png();
+        let truncated = &data[..data.len() - 6];
+        assert!(check_structure(truncated).unwrap_err().contains("truncated"));
+
+        // 长度字段为 u32::MAX 时不能溢出
+        let mut huge = PNG_SIGNATURE.to_vec();
+        huge.extend(u32::MAX.to_be_bytes());
+        huge.extend(b"IDAT");
+        huge.extend([0u8; 8]);
+        assert!(check_structure(&huge).unwrap_err().contains("truncated"));
+    }
+
+    #[test]
+    fn gif_trailer() {
+        let mut data = b"GIF89a".to_vec();
+        data.extend([0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        assert!(check_structure(&data).unwrap_err().contains("trailer"));
+
+        data.push(0x3B);
+        assert!(check_structure(&data).is_ok());
+    }
+
+    #[test]
+    fn webp_riff_length() {
+        assert!(check_structure(&webp()).is_ok());
+
+        let mut data = webp();
+        data[4..8].copy_from_slice(&32u32.to_le_bytes());
+        assert!(check_structure(&data).unwrap_err().contains("RIFF length"));
+
+        let truncated = &webp()[..20];
+        assert!(check_structure(truncated).is_err());
+    }
+}
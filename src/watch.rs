@@ -0,0 +1,96 @@
+use crate::handler::Handler;
+use crate::library::Library;
+use crate::logger;
+use crate::source::{self, Source};
+use reqwest::Url;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// watch 子命令的配置文件(toml)
+///
+/// ```toml
+/// interval = 3600            # 守护模式下两次检查之间的间隔(秒)
+/// limit = 25                 # 每个搜索默认最多检查的图集数量
+///
+/// [[search]]
+/// name = "artist example"
+/// url = "https://e-hentai.org/tag/artist:example"
+/// limit = 10
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct WatchConfig {
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+    #[serde(default, rename = "search")]
+    pub searches: Vec<SavedSearch>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SavedSearch {
+    pub name: String,
+    pub url: String,
+    pub limit: Option<usize>,
+}
+
+fn default_interval() -> u64 {
+    3600
+}
+
+fn default_limit() -> usize {
+    25
+}
+
+impl WatchConfig {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path)?;
+        let config: WatchConfig = toml::from_str(&text)?;
+        for search in &config.searches {
+            let url = Url::parse(&search.url)?;
+            // 和命令行的url一样只接受 https 的 e-hentai/exhentai，cookie 不能发给其他主机
+            let host = url.host_str().unwrap_or_default();
+            if url.scheme() != "https" || (host != "e-hentai.org" && host != "exhentai.org") {
+                return Err(format!(
+                    "'{}' must be an https url on e-hentai.org or exhentai.org: {}",
+                    search.name, search.url
+                )
+                .into());
+            }
+            match Source::from_url(&url) {
+                Some(s) if s.is_listing() => {}
+                _ => return Err(format!("'{}' is not a listing url: {}", search.name, search.url).into()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// 拉取每个保存的搜索的最新结果，只返回本地图集库中还没有完整下载的图集
+pub fn collect_new(config: &WatchConfig, cookie: &str, library: &Library) -> Vec<Url> {
+    let mut queue: Vec<Url> = vec![];
+
+    for search in &config.searches {
+        // 配置加载时已经校验过url
+        let url = Url::parse(&search.url).unwrap();
+        let host = url.host_str().unwrap_or_default().to_string();
+        let h = Handler::new(&host, cookie);
+
+        let limit = search.limit.unwrap_or(config.limit);
+        let galleries = source::expand_listing(&h, &url, Some(limit));
+
+        let new: Vec<Url> = galleries
+            .into_iter()
+            .filter(|g| {
+                source::gallery_id(g)
+                    .is_some_and(|id| !library.get(id).is_some_and(|e| e.is_available()))
+            })
+            .filter(|g| !queue.iter().any(|q| source::gallery_id(q) == source::gallery_id(g)))
+            .collect();
+        logger::info(&format!("[watch] {}: {} new galleries", search.name, new.len()));
+        queue.extend(new);
+    }
+
+    queue
+}